@@ -0,0 +1,62 @@
+// instructions per second on a CPU-bound program, plain and with each kind of
+// instrumentation on, to catch a feature slowing down the interpreter loop.
+// run with `cargo bench`, the rates print as each case finishes
+//
+// out of scope: a match-based against table-based dispatch comparison. the
+// VM only has the match in VM::step, a table would have to be written just
+// to be measured here. it's a plain harness rather than criterion so the
+// crate keeps no dependency for it
+use std::io;
+use std::time::Instant;
+use lc3_vm_rust::assemble;
+use lc3_vm_rust::vm::{CycleCosts, VM};
+
+// a tight arithmetic loop: R2 runs through every 16 bit value OUTER times,
+// with loads, stores and a subroutine call in the body
+const LOOP: &str = "\
+.ORIG x3000
+        LD R3, OUTER
+OUTER_L AND R2, R2, #0
+INNER   ADD R1, R1, R2
+        NOT R4, R1
+        AND R4, R4, #15
+        ST R4, SCRATCH
+        LDR R5, R6, #0
+        JSR STEP
+        ADD R2, R2, #1
+        BRnp INNER
+        ADD R3, R3, #-1
+        BRp OUTER_L
+        HALT
+STEP    ADD R0, R0, #1
+        RET
+OUTER   .FILL #32
+SCRATCH .BLKW 1
+.END
+";
+
+fn fresh(image: &[u8]) -> VM {
+    let mut vm = VM::with_io(Box::new(io::empty()), Box::new(io::sink()));
+    vm.load_bytes(image).expect("the benchmark image loads");
+    vm
+}
+
+fn bench(name: &str, image: &[u8], setup: fn(&mut VM)) {
+    let mut vm = fresh(image);
+    setup(&mut vm);
+    let start = Instant::now();
+    vm.run();
+    let elapsed = start.elapsed().as_secs_f64();
+    let rate = vm.instructions() as f64 / elapsed;
+    println!("{:<16} {:>11} instructions in {:.3}s, {:>6.1}M instructions/s", name, vm.instructions(), elapsed, rate / 1e6);
+}
+
+fn main() {
+    let image = assemble(LOOP).expect("the benchmark source assembles").to_bytes();
+    bench("plain", &image, |_| {});
+    bench("cycle costs", &image, |vm| vm.set_cycle_costs(Some(CycleCosts::default())));
+    bench("branch stats", &image, VM::enable_branch_stats);
+    bench("coverage", &image, |vm| vm.enable_coverage(&[(0x3000, 0x20)]));
+    bench("post-step hook", &image, |vm| vm.set_post_step_hook(Box::new(|_, _, _| {})));
+    bench("history", &image, |vm| vm.set_history(100_000));
+}
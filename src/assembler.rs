@@ -0,0 +1,728 @@
+use std::collections::BTreeMap;
+use std::{fmt, fs};
+use crate::consts::{
+    OP_ADD, OP_AND, OP_JMP, OP_JSR, OP_LD, OP_LDI, OP_LDR, OP_LEA, OP_NOT, OP_RTI, OP_ST, OP_STI, OP_STR, OP_TRAP,
+    TRAP_GETC, TRAP_HALT, TRAP_IN, TRAP_OUT, TRAP_PUTS, TRAP_PUTSP,
+};
+use crate::image::{sections_to_bytes, Image};
+use crate::link::{Object, RefKind, Reference};
+
+// a two-pass assembler for LC-3 assembly source
+// pass 1 collects the label addresses, pass 2 encodes the instructions
+
+// an assembled program: the sections in source order and the label addresses,
+// with the labels it exports by .GLOBAL and its uses of .EXTERNAL labels
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub sections: Vec<Image>,
+    pub symbols: BTreeMap<String, u16>,
+    pub exports: BTreeMap<String, u16>,
+    pub references: Vec<Reference>,
+}
+
+impl Program {
+    // the origin of the first section
+    pub fn origin(&self) -> u16 {
+        self.sections[0].origin
+    }
+
+    // encode the program as an object file, see image::sections_to_bytes.
+    // a program taking part in linking is written as a linkable object instead
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if self.exports.is_empty() && self.references.is_empty() {
+            sections_to_bytes(&self.sections)
+        } else {
+            self.to_object().to_bytes()
+        }
+    }
+
+    pub fn to_object(&self) -> Object {
+        Object {
+            sections: self.sections.clone(),
+            exports: self.exports.clone(),
+            references: self.references.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+fn error(line: usize, message: impl Into<String>) -> AsmError {
+    AsmError { line, message: message.into() }
+}
+
+fn err<T>(line: usize, message: impl Into<String>) -> Result<T, AsmError> {
+    Err(error(line, message))
+}
+
+// one source line after tokenizing
+struct Line {
+    number: usize,
+    section: usize,
+    op: Option<String>,
+    operands: Vec<String>,
+    address: u16,
+}
+
+const OPCODES: [&str; 22] = [
+    "ADD", "AND", "NOT", "JMP", "RET", "JSR", "JSRR", "LD", "LDI", "LDR", "LEA", "ST", "STI",
+    "STR", "TRAP", "RTI", "GETC", "OUT", "PUTS", "IN", "PUTSP", "HALT",
+];
+
+// BR, BRn, BRzp, ... with the flags in any order
+fn is_branch(op: &str) -> bool {
+    op.starts_with("BR") && op[2..].chars().all(|c| matches!(c, 'N' | 'Z' | 'P'))
+}
+
+fn is_op(token: &str) -> bool {
+    let upper = token.to_ascii_uppercase();
+    upper.starts_with('.') || OPCODES.contains(&upper.as_str()) || is_branch(&upper)
+}
+
+// strip a ';' comment, ignoring ';' inside a string literal
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\\' if in_string => escaped = !escaped,
+            '"' if !escaped => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => escaped = false,
+        }
+        if c != '\\' {
+            escaped = false;
+        }
+    }
+    line
+}
+
+// split a line into tokens on whitespace and commas, keeping string literals whole
+fn tokenize(line: &str, number: usize) -> Result<Vec<String>, AsmError> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || c == ',' {
+            chars.next();
+        } else if c == '"' {
+            let mut token = String::from('"');
+            chars.next();
+            let mut closed = false;
+            while let Some(c) = chars.next() {
+                token.push(c);
+                if c == '\\' {
+                    if let Some(n) = chars.next() {
+                        token.push(n);
+                    }
+                } else if c == '"' {
+                    closed = true;
+                    break;
+                }
+            }
+            if !closed {
+                return err(number, "unterminated string literal");
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ',' {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+// decode the escapes \n \t \r \0 \e \\ and \" in text
+pub fn unescape(text: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('e') => '\x1b',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            Some(o) => return Err(format!("unknown escape '\\{}'", o)),
+            None => return Err("dangling '\\' in string".to_string()),
+        });
+    }
+    Ok(out)
+}
+
+// decode the escapes of a "..." literal
+fn parse_string(token: &str, line: usize) -> Result<Vec<u16>, AsmError> {
+    if token.len() < 2 || !token.starts_with('"') || !token.ends_with('"') {
+        return err(line, format!("expected a string literal, found '{}'", token));
+    }
+    let text = unescape(&token[1..token.len() - 1]).map_err(|e| error(line, e))?;
+    if let Some(c) = text.chars().find(|c| !c.is_ascii()) {
+        return err(line, format!("non-ASCII character '{}' in string", c));
+    }
+    Ok(text.chars().map(|c| c as u16).collect())
+}
+
+// the words .INCBIN "file" emits: the host file's bytes packed big-endian two
+// to a word, an odd last byte padded with a zero low byte. the path is taken
+// from the current directory, not the source file's
+fn incbin(token: &str, line: usize) -> Result<Vec<u16>, AsmError> {
+    let path: String = parse_string(token, line)?.iter().map(|&c| c as u8 as char).collect();
+    let bytes = fs::read(&path).map_err(|e| error(line, format!("cannot read .INCBIN file '{}': {}", path, e)))?;
+    Ok(bytes.chunks(2).map(|p| (p[0] as u16) << 8 | *p.get(1).unwrap_or(&0) as u16).collect())
+}
+
+// parse a numeric literal: #10, #-3, x3000, 0x3000, b1010 or a bare decimal
+pub fn parse_number(token: &str) -> Option<i32> {
+    let (radix, digits) = if let Some(d) = token.strip_prefix('#') {
+        (10, d)
+    } else if let Some(h) = token.strip_prefix("0x").or_else(|| token.strip_prefix("0X")) {
+        (16, h)
+    } else if let Some(h) = token.strip_prefix('x').or_else(|| token.strip_prefix('X')) {
+        (16, h)
+    } else if let Some(b) = token.strip_prefix('b').or_else(|| token.strip_prefix('B')) {
+        (2, b)
+    } else {
+        (10, token)
+    };
+    let (neg, digits) = match digits.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, digits),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    let value = i32::from_str_radix(digits, radix).ok()?;
+    Some(if neg { -value } else { value })
+}
+
+fn parse_register(token: &str) -> Option<u16> {
+    let upper = token.to_ascii_uppercase();
+    let n = upper.strip_prefix('R')?;
+    match n.parse::<u16>() {
+        Ok(i) if i < 8 && n.len() == 1 => Some(i),
+        _ => None,
+    }
+}
+
+struct Assembler<'a> {
+    symbols: &'a BTreeMap<String, u16>,
+    externals: &'a BTreeMap<String, usize>,
+    // the uses of external labels, left for the linker
+    references: Vec<Reference>,
+}
+
+impl Assembler<'_> {
+    fn register(&self, line: &Line, i: usize) -> Result<u16, AsmError> {
+        let token = self.operand(line, i)?;
+        parse_register(token)
+            .ok_or_else(|| error(line.number, format!("expected a register, found '{}'", token)))
+    }
+
+    fn operand<'l>(&self, line: &'l Line, i: usize) -> Result<&'l str, AsmError> {
+        line.operands
+            .get(i)
+            .map(|s| s.as_str())
+            .ok_or_else(|| error(line.number, format!("missing operand {}", i + 1)))
+    }
+
+    fn expect_operands(&self, line: &Line, n: usize) -> Result<(), AsmError> {
+        if line.operands.len() != n {
+            return err(line.number, format!(
+                "{} expects {} operand(s), found {}",
+                line.op.as_deref().unwrap_or(""),
+                n,
+                line.operands.len()
+            ));
+        }
+        Ok(())
+    }
+
+    // an immediate that must fit in bits as a signed value
+    fn immediate(&self, line: &Line, i: usize, bits: u32) -> Result<u16, AsmError> {
+        let token = self.operand(line, i)?;
+        let v = parse_number(token)
+            .ok_or_else(|| error(line.number, format!("expected an immediate, found '{}'", token)))?;
+        let min = -(1 << (bits - 1));
+        let max = (1 << (bits - 1)) - 1;
+        if v < min || v > max {
+            return err(line.number, format!("immediate {} does not fit in {} bits", v, bits));
+        }
+        Ok((v as u16) & ((1 << bits) - 1))
+    }
+
+    // a PC-relative offset to a label or a literal offset, an external
+    // label is encoded as 0 for the linker to fill in
+    fn offset(&mut self, line: &Line, i: usize, bits: u32) -> Result<u16, AsmError> {
+        let token = self.operand(line, i)?;
+        if self.externals.contains_key(token) {
+            let kind = if bits == 9 { RefKind::Offset9 } else { RefKind::Offset11 };
+            self.references.push(Reference { address: line.address, kind, symbol: token.to_string() });
+            return Ok(0);
+        }
+        let label = self.symbols.get(token);
+        let v = match label {
+            Some(&target) => target as i32 - (line.address as i32 + 1),
+            None => parse_number(token).ok_or_else(|| error(line.number, format!("undefined label '{}'", token)))?,
+        };
+        // masking an offset that doesn't fit would silently jump or load somewhere else
+        let min = -(1 << (bits - 1));
+        let max = (1 << (bits - 1)) - 1;
+        if v < min || v > max {
+            let what = match label {
+                Some(_) => format!("label '{}' is {} words away", token, v),
+                None => format!("offset {}", v),
+            };
+            return err(line.number, format!("{}, a {} bit offset reaches {}..{}", what, bits, min, max));
+        }
+        Ok((v as u16) & ((1 << bits) - 1))
+    }
+
+    fn encode(&mut self, line: &Line, op: &str) -> Result<u16, AsmError> {
+        let word = match op {
+            "ADD" | "AND" => {
+                self.expect_operands(line, 3)?;
+                let opcode = if op == "ADD" { OP_ADD } else { OP_AND };
+                let dr = self.register(line, 0)?;
+                let sr1 = self.register(line, 1)?;
+                let last = if parse_register(&line.operands[2]).is_some() {
+                    self.register(line, 2)?
+                } else {
+                    1 << 5 | self.immediate(line, 2, 5)?
+                };
+                opcode << 12 | dr << 9 | sr1 << 6 | last
+            }
+            "NOT" => {
+                self.expect_operands(line, 2)?;
+                OP_NOT << 12 | self.register(line, 0)? << 9 | self.register(line, 1)? << 6 | 0x3f
+            }
+            "JMP" => {
+                self.expect_operands(line, 1)?;
+                OP_JMP << 12 | self.register(line, 0)? << 6
+            }
+            "RET" => {
+                self.expect_operands(line, 0)?;
+                OP_JMP << 12 | 7 << 6
+            }
+            "JSR" => {
+                self.expect_operands(line, 1)?;
+                OP_JSR << 12 | 1 << 11 | self.offset(line, 0, 11)?
+            }
+            "JSRR" => {
+                self.expect_operands(line, 1)?;
+                OP_JSR << 12 | self.register(line, 0)? << 6
+            }
+            "LD" | "LDI" | "LEA" | "ST" | "STI" => {
+                self.expect_operands(line, 2)?;
+                let opcode = match op {
+                    "LD" => OP_LD,
+                    "LDI" => OP_LDI,
+                    "LEA" => OP_LEA,
+                    "ST" => OP_ST,
+                    _ => OP_STI,
+                };
+                opcode << 12 | self.register(line, 0)? << 9 | self.offset(line, 1, 9)?
+            }
+            "LDR" | "STR" => {
+                self.expect_operands(line, 3)?;
+                let opcode = if op == "LDR" { OP_LDR } else { OP_STR };
+                opcode << 12
+                    | self.register(line, 0)? << 9
+                    | self.register(line, 1)? << 6
+                    | self.immediate(line, 2, 6)?
+            }
+            "TRAP" => {
+                self.expect_operands(line, 1)?;
+                let token = self.operand(line, 0)?;
+                match parse_number(token) {
+                    Some(v) if (0..=0xff).contains(&v) => OP_TRAP << 12 | v as u16,
+                    _ => return err(line.number, format!("invalid trap vector '{}'", token)),
+                }
+            }
+            "RTI" => {
+                self.expect_operands(line, 0)?;
+                OP_RTI << 12
+            }
+            "GETC" | "OUT" | "PUTS" | "IN" | "PUTSP" | "HALT" => {
+                self.expect_operands(line, 0)?;
+                let vector = match op {
+                    "GETC" => TRAP_GETC,
+                    "OUT" => TRAP_OUT,
+                    "PUTS" => TRAP_PUTS,
+                    "IN" => TRAP_IN,
+                    "PUTSP" => TRAP_PUTSP,
+                    _ => TRAP_HALT,
+                };
+                OP_TRAP << 12 | vector
+            }
+            _ if is_branch(op) => {
+                self.expect_operands(line, 1)?;
+                // a bare BR is unconditional, the same as BRnzp
+                let flags = if op == "BR" { "NZP" } else { &op[2..] };
+                let mut nzp = 0;
+                if flags.contains('N') {
+                    nzp |= 0x4;
+                }
+                if flags.contains('Z') {
+                    nzp |= 0x2;
+                }
+                if flags.contains('P') {
+                    nzp |= 0x1;
+                }
+                nzp << 9 | self.offset(line, 0, 9)?
+            }
+            _ => return err(line.number, format!("unknown instruction '{}'", op)),
+        };
+        Ok(word)
+    }
+}
+
+// assemble one instruction as if placed at address, for patching memory.
+// PC-relative operands have to be numbers, there are no labels to refer to
+pub fn assemble_one(address: u16, text: &str) -> Result<u16, String> {
+    let program = assemble(&format!(".ORIG x{:04X}\n{}\n.END\n", address, text)).map_err(|e| e.message)?;
+    match program.sections[0].words[..] {
+        [word] => Ok(word),
+        _ => Err("expected a single instruction".to_string()),
+    }
+}
+
+// assemble LC-3 source into a program, each .ORIG/.END block becomes a section
+pub fn assemble(src: &str) -> Result<Program, AsmError> {
+    // pass 1: tokenize and assign addresses
+    let mut lines = Vec::new();
+    let mut symbols = BTreeMap::new();
+    // the origin of each section and the line of its .ORIG
+    let mut origins: Vec<(u16, usize)> = Vec::new();
+    let mut open = false;
+    let mut address: u32 = 0;
+    // labels defined in another file and labels defined here for other files, with their lines
+    let mut externals = BTreeMap::new();
+    let mut globals: Vec<(String, usize)> = Vec::new();
+    // the words of each .INCBIN by line, read once in pass 1 to size it
+    let mut binaries: BTreeMap<usize, Vec<u16>> = BTreeMap::new();
+    for (i, raw) in src.lines().enumerate() {
+        let number = i + 1;
+        let mut tokens = tokenize(strip_comment(raw), number)?.into_iter();
+        let mut label = None;
+        let mut op = None;
+        if let Some(first) = tokens.next() {
+            if is_op(&first) {
+                op = Some(first.to_ascii_uppercase());
+            } else {
+                label = Some(first.trim_end_matches(':').to_string());
+                op = tokens.next().map(|t| t.to_ascii_uppercase());
+            }
+        }
+        let operands: Vec<String> = tokens.collect();
+        if label.is_none() && op.is_none() {
+            continue;
+        }
+        if let Some(op) = &op {
+            if !is_op(op) {
+                return err(number, format!("unknown instruction '{}'", op));
+            }
+        }
+
+        if let Some(directive @ (".EXTERNAL" | ".GLOBAL")) = op.as_deref() {
+            if label.is_some() || operands.is_empty() {
+                return err(number, format!("{} expects label names", directive));
+            }
+            for name in operands {
+                if directive == ".EXTERNAL" {
+                    externals.insert(name, number);
+                } else {
+                    globals.push((name, number));
+                }
+            }
+            continue;
+        }
+        if op.as_deref() == Some(".ORIG") {
+            let value = operands.first().and_then(|t| parse_number(t));
+            match value {
+                Some(v) if (0..=0xffff).contains(&v) => {
+                    origins.push((v as u16, number));
+                    address = v as u32;
+                    open = true;
+                }
+                _ => return err(number, ".ORIG expects an address"),
+            }
+            continue;
+        }
+        if op.as_deref() == Some(".END") {
+            open = false;
+            continue;
+        }
+        if !open {
+            return err(number, "code outside of an .ORIG/.END section");
+        }
+        if let Some(name) = &label {
+            if symbols.insert(name.clone(), address as u16).is_some() {
+                return err(number, format!("duplicate label '{}'", name));
+            }
+        }
+        let size = match op.as_deref() {
+            None => 0,
+            Some(".FILL") => 1,
+            Some(".BLKW") => match operands.first().and_then(|t| parse_number(t)) {
+                Some(n) if n >= 0 => n as u32,
+                _ => return err(number, ".BLKW expects a word count"),
+            },
+            Some(".STRINGZ") => match operands.first() {
+                Some(s) => parse_string(s, number)?.len() as u32 + 1,
+                None => return err(number, ".STRINGZ expects a string"),
+            },
+            Some(".INCBIN") => match operands.first() {
+                Some(s) => {
+                    let words = incbin(s, number)?;
+                    let size = words.len() as u32;
+                    binaries.insert(number, words);
+                    size
+                }
+                None => return err(number, ".INCBIN expects a file name"),
+            },
+            Some(d) if d.starts_with('.') => return err(number, format!("unknown directive '{}'", d)),
+            Some(_) => 1,
+        };
+        if address + size > 0x10000 {
+            return err(number, "program runs past the end of memory");
+        }
+        lines.push(Line { number, section: origins.len() - 1, op, operands, address: address as u16 });
+        address += size;
+    }
+    if origins.is_empty() {
+        return err(0, "missing .ORIG");
+    }
+    if let Some((name, &number)) = externals.iter().find(|(n, _)| symbols.contains_key(*n)) {
+        return err(number, format!("label '{}' is declared .EXTERNAL but defined here", name));
+    }
+    let mut exports = BTreeMap::new();
+    for (name, number) in globals {
+        match symbols.get(&name) {
+            Some(&a) => exports.insert(name, a),
+            None => return err(number, format!(".GLOBAL label '{}' is not defined", name)),
+        };
+    }
+
+    // pass 2: encode
+    let mut asm = Assembler { symbols: &symbols, externals: &externals, references: Vec::new() };
+    let mut sections: Vec<Image> =
+        origins.iter().map(|&(origin, _)| Image { origin, words: Vec::new() }).collect();
+    for line in &lines {
+        let op = match &line.op {
+            Some(op) => op.as_str(),
+            None => continue,
+        };
+        let words = &mut sections[line.section].words;
+        match op {
+            ".FILL" => {
+                asm.expect_operands(line, 1)?;
+                let token = &line.operands[0];
+                if externals.contains_key(token) {
+                    asm.references.push(Reference { address: line.address, kind: RefKind::Word, symbol: token.clone() });
+                }
+                let value = match symbols.get(token) {
+                    Some(&a) => a as i32,
+                    None if externals.contains_key(token) => 0,
+                    None => parse_number(token).ok_or_else(|| error(line.number, format!("undefined label '{}'", token)))?,
+                };
+                if !(-0x8000..=0xffff).contains(&value) {
+                    return err(line.number, format!("value {} does not fit in a word", value));
+                }
+                words.push(value as u16);
+            }
+            ".BLKW" => {
+                // .BLKW n or .BLKW n, fill, the words are zeroed by default
+                if line.operands.len() != 2 {
+                    asm.expect_operands(line, 1)?;
+                }
+                let n = parse_number(&line.operands[0]).unwrap_or(0) as usize;
+                let fill = match line.operands.get(1) {
+                    Some(token) => match parse_number(token) {
+                        Some(v) if (-0x8000..=0xffff).contains(&v) => v as u16,
+                        _ => return err(line.number, format!("invalid .BLKW fill value '{}'", token)),
+                    },
+                    None => 0,
+                };
+                words.extend(std::iter::repeat_n(fill, n));
+            }
+            ".STRINGZ" => {
+                asm.expect_operands(line, 1)?;
+                words.extend(parse_string(&line.operands[0], line.number)?);
+                words.push(0);
+            }
+            ".INCBIN" => {
+                asm.expect_operands(line, 1)?;
+                words.extend(binaries.remove(&line.number).unwrap_or_default());
+            }
+            _ => words.push(asm.encode(line, op)?),
+        }
+    }
+
+    // sections may come in any order but must not share an address. in
+    // origin order each has to start past the furthest any before it
+    // reaches, an empty section takes no room
+    let mut order: Vec<usize> = (0..sections.len()).filter(|&i| !sections[i].words.is_empty()).collect();
+    order.sort_by_key(|&i| sections[i].origin);
+    let mut furthest: Option<&Image> = None;
+    for i in order {
+        let b = &sections[i];
+        if let Some(a) = furthest.filter(|a| a.end() > b.origin as u32) {
+            return err(origins[i].1, format!(
+                "section at x{:04X} overlaps the section at x{:04X}",
+                b.origin, a.origin
+            ));
+        }
+        if furthest.is_none_or(|a| b.end() > a.end()) {
+            furthest = Some(b);
+        }
+    }
+    let references = asm.references;
+    Ok(Program { sections, symbols, exports, references })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the words of the only section of src
+    fn words(src: &str) -> Vec<u16> {
+        let program = assemble(src).unwrap();
+        assert_eq!(program.sections.len(), 1);
+        program.sections[0].words.clone()
+    }
+
+    #[test]
+    fn bare_br_is_brnzp() {
+        let words = words(".ORIG x3000\nBR NEXT\nBR NEXT\nNEXT BRnzp NEXT\n.END\n");
+        assert_eq!(words[0], 0x0E00 | 1);
+        assert_eq!(words[1], 0x0E00);
+        assert_eq!(words[2], 0x0E00 | 0x1FF);
+    }
+
+    #[test]
+    fn br_conditions_encode_nzp() {
+        let words = words(".ORIG x3000\nL BRn L\nBRz L\nBRp L\nBRzp L\nBRnp L\nBRnz L\n.END\n");
+        let nzp: Vec<u16> = words.iter().map(|w| w >> 9).collect();
+        assert_eq!(nzp, [0b100, 0b010, 0b001, 0b011, 0b101, 0b110]);
+    }
+
+    // a file of bytes for .INCBIN, unique to the test and this run
+    fn binary(name: &str, bytes: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("lc3-incbin-{}-{}.bin", std::process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn incbin_packs_bytes_big_endian() {
+        let path = binary("even", &[0x12, 0x34, 0xAB, 0xCD]);
+        let program = assemble(&format!(".ORIG x3000\n.INCBIN \"{}\"\nEND .FILL #7\n.END\n", path)).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(program.sections[0].words, [0x1234, 0xABCD, 7]);
+        assert_eq!(program.symbols["END"], 0x3002);
+    }
+
+    #[test]
+    fn incbin_pads_an_odd_byte() {
+        let path = binary("odd", &[0x12, 0x34, 0x56]);
+        let words = words(&format!(".ORIG x3000\n.INCBIN \"{}\"\n.END\n", path));
+        fs::remove_file(&path).unwrap();
+        assert_eq!(words, [0x1234, 0x5600]);
+    }
+
+    #[test]
+    fn incbin_of_a_missing_file_fails() {
+        let e = assemble(".ORIG x3000\n.INCBIN \"/nonexistent/lc3.bin\"\n.END\n").unwrap_err();
+        assert!(e.to_string().contains("cannot read .INCBIN file '/nonexistent/lc3.bin'"), "{}", e);
+    }
+
+    #[test]
+    fn blkw_fills_with_the_value_given() {
+        let words = words(".ORIG x3000\n.BLKW 3, 0xFF\n.BLKW 2\n.BLKW 1, #-1\n.END\n");
+        assert_eq!(words, [0x00FF, 0x00FF, 0x00FF, 0, 0, 0xFFFF]);
+    }
+
+    #[test]
+    fn blkw_fill_out_of_range_fails() {
+        for fill in ["x10000", "#-32769", "LABEL"] {
+            let e = assemble(&format!(".ORIG x3000\n.BLKW 3, {}\n.END\n", fill)).unwrap_err();
+            assert!(e.to_string().contains(&format!("invalid .BLKW fill value '{}'", fill)), "{}", e);
+        }
+    }
+
+    #[test]
+    fn sections_in_any_order_assemble() {
+        let program = assemble(".ORIG x4000\n.FILL #2\n.END\n.ORIG x3000\n.BLKW 2\n.END\n.ORIG x3002\n.FILL #1\n.END\n").unwrap();
+        let origins: Vec<u16> = program.sections.iter().map(|s| s.origin).collect();
+        assert_eq!(origins, [0x4000, 0x3000, 0x3002]);
+    }
+
+    #[test]
+    fn overlap_is_found_past_an_empty_section() {
+        // x3010 is empty, x3020 still lands inside x3000-x30FF
+        let src = ".ORIG x3000\n.BLKW x100\n.END\n.ORIG x3010\n.END\n.ORIG x3020\n.FILL #1\n.END\n";
+        let e = assemble(src).unwrap_err();
+        assert!(e.to_string().contains("section at x3020 overlaps the section at x3000"), "{}", e);
+    }
+
+    #[test]
+    fn pc_offset9_reaches_its_bounds_and_no_further() {
+        // FAR follows n words after the LD, n words away from the incremented PC
+        let forward = |n: u32| assemble(&format!(".ORIG x3000\nLD R0, FAR\n.BLKW {}\nFAR .FILL #1\n.END\n", n));
+        assert_eq!(forward(255).unwrap().sections[0].words[0], 0x20FF);
+        let e = forward(256).unwrap_err();
+        assert_eq!((e.line, e.message.as_str()), (2, "label 'FAR' is 256 words away, a 9 bit offset reaches -256..255"));
+        let back = |n: u32| assemble(&format!(".ORIG x3000\nBACK .FILL #1\n.BLKW {}\nBRnzp BACK\n.END\n", n));
+        assert_eq!(back(254).unwrap().sections[0].words[255], 0x0F00);
+        let e = back(255).unwrap_err();
+        assert_eq!((e.line, e.message.as_str()), (4, "label 'BACK' is -257 words away, a 9 bit offset reaches -256..255"));
+    }
+
+    #[test]
+    fn pc_offset11_reaches_its_bounds_and_no_further() {
+        let forward = |n: u32| assemble(&format!(".ORIG x3000\nJSR FAR\n.BLKW {}\nFAR RET\n.END\n", n));
+        assert_eq!(forward(1023).unwrap().sections[0].words[0], 0x4BFF);
+        let e = forward(1024).unwrap_err();
+        assert_eq!(e.line, 2);
+        assert_eq!(e.message, "label 'FAR' is 1024 words away, a 11 bit offset reaches -1024..1023");
+        let back = |n: u32| assemble(&format!(".ORIG x3000\nBACK RET\n.BLKW {}\nJSR BACK\n.END\n", n));
+        assert_eq!(back(1022).unwrap().sections[0].words[1023], 0x4C00);
+        let e = back(1023).unwrap_err();
+        assert_eq!(e.line, 4);
+        assert!(e.message.starts_with("label 'BACK' is -1025 words away"), "{}", e);
+    }
+
+    #[test]
+    fn offset6_reaches_its_bounds_and_no_further() {
+        assert_eq!(words(".ORIG x3000\nLDR R0, R1, #31\nSTR R0, R1, #-32\n.END\n"), [0x605F, 0x7060]);
+        let cases = [("LDR R0, R1, #32", "immediate 32 does not fit in 6 bits"), ("STR R0, R1, #-33", "immediate -33 does not fit in 6 bits")];
+        for (src, message) in cases {
+            let e = assemble(&format!(".ORIG x3000\nADD R0, R0, #1\n{}\n.END\n", src)).unwrap_err();
+            assert_eq!((e.line, e.message.as_str()), (3, message));
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+use crate::disasm::disassemble;
+use crate::vm::VM;
+
+// how often each BR was taken and not taken, keyed by the branch's address
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCount {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BranchStats {
+    counts: BTreeMap<u16, BranchCount>,
+}
+
+impl BranchStats {
+    pub fn new() -> BranchStats {
+        BranchStats::default()
+    }
+
+    pub fn record(&mut self, address: u16, taken: bool) {
+        let count = self.counts.entry(address).or_default();
+        if taken {
+            count.taken += 1;
+        } else {
+            count.not_taken += 1;
+        }
+    }
+
+    pub fn get(&self, address: u16) -> Option<BranchCount> {
+        self.counts.get(&address).copied()
+    }
+
+    // every branch executed, in address order
+    pub fn iter(&self) -> impl Iterator<Item = (u16, BranchCount)> + '_ {
+        self.counts.iter().map(|(&a, &c)| (a, c))
+    }
+
+    // print a line per branch executed, busiest first
+    pub fn report(&self, vm: &VM, out: &mut dyn Write) -> io::Result<()> {
+        let mut branches: Vec<_> = self.iter().collect();
+        branches.sort_by_key(|(_, c)| std::cmp::Reverse(c.taken + c.not_taken));
+        writeln!(out, "{} branches executed", branches.len())?;
+        for (address, c) in branches {
+            let text = disassemble(vm.fetch(address), address);
+            writeln!(out, "  x{:04X}  {:<16} taken {:>8}  not taken {:>8}", address, text, c.taken, c.not_taken)?;
+        }
+        Ok(())
+    }
+}
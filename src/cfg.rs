@@ -0,0 +1,75 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::io::Write;
+use crate::disasm::disassemble;
+use crate::vm::VM;
+
+// the PCs a run executed and which PC followed which, a crude control flow graph
+#[derive(Default)]
+pub struct FlowGraph {
+    visited: BTreeSet<u16>,
+    successors: BTreeMap<u16, BTreeSet<u16>>,
+    predecessors: BTreeMap<u16, BTreeSet<u16>>,
+}
+
+impl FlowGraph {
+    pub fn new() -> FlowGraph {
+        FlowGraph::default()
+    }
+
+    // the instruction at pc executed, next is where execution went on or None if it stopped
+    pub fn record(&mut self, pc: u16, next: Option<u16>) {
+        self.visited.insert(pc);
+        if let Some(next) = next {
+            self.successors.entry(pc).or_default().insert(next);
+            self.predecessors.entry(next).or_default().insert(pc);
+        }
+    }
+
+    pub fn visited(&self) -> &BTreeSet<u16> {
+        &self.visited
+    }
+
+    // pc went on to only the next word, which is reached only from pc
+    fn falls_through(&self, pc: u16) -> Option<u16> {
+        let next = pc.wrapping_add(1);
+        let only = |set: Option<&BTreeSet<u16>>, v: u16| set.is_some_and(|s| s.len() == 1 && s.contains(&v));
+        (only(self.successors.get(&pc), next) && only(self.predecessors.get(&next), pc)).then_some(next)
+    }
+
+    // the executed PCs split into basic blocks, each a run of consecutive
+    // addresses entered only at its first and left only at its last
+    pub fn blocks(&self) -> Vec<Vec<u16>> {
+        let continues: BTreeSet<u16> = self.visited.iter().filter_map(|&pc| self.falls_through(pc)).collect();
+        let mut blocks = Vec::new();
+        for &leader in self.visited.iter().filter(|pc| !continues.contains(pc)) {
+            let mut block = vec![leader];
+            while let Some(next) = self.falls_through(*block.last().unwrap()) {
+                block.push(next);
+            }
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    // write the blocks as a DOT graph labelled with their disassembly
+    pub fn write_dot(&self, vm: &VM, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "digraph cfg {{")?;
+        writeln!(out, "    node [shape=box, fontname=monospace];")?;
+        let blocks = self.blocks();
+        for block in &blocks {
+            let label: String = block
+                .iter()
+                .map(|&pc| format!("x{:04X}  {}\\l", pc, disassemble(vm.fetch(pc), pc).replace('\\', "\\\\").replace('"', "\\\"")))
+                .collect();
+            writeln!(out, "    b{:04X} [label=\"{}\"];", block[0], label)?;
+        }
+        for block in &blocks {
+            let last = block.last().unwrap();
+            for next in self.successors.get(last).into_iter().flatten() {
+                writeln!(out, "    b{:04X} -> b{:04X};", block[0], next)?;
+            }
+        }
+        writeln!(out, "}}")
+    }
+}
@@ -0,0 +1,46 @@
+use std::env;
+use std::io::{stdout, IsTerminal};
+
+// ANSI coloring of disassembly, kept apart so plain output never sees an escape
+
+const OPCODE: &str = "\x1b[33m";
+const REGISTER: &str = "\x1b[36m";
+const NUMBER: &str = "\x1b[35m";
+const COMMENT: &str = "\x1b[2m";
+const RESET: &str = "\x1b[0m";
+
+// color unless --no-color was given, NO_COLOR is set or stdout isn't a terminal
+pub fn enabled(no_color: bool) -> bool {
+    enabled_on(no_color, &stdout())
+}
+
+// the same for output going to stream instead, e.g. --trace on stderr
+pub fn enabled_on(no_color: bool, stream: &impl IsTerminal) -> bool {
+    let no_color_env = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    !no_color && !no_color_env && stream.is_terminal()
+}
+
+fn paint(color: &str, text: &str) -> String {
+    format!("{}{}{}", color, text, RESET)
+}
+
+// color a disassembled instruction: the mnemonic, the registers and the
+// immediates/addresses each get their own color, a trailing comment is dimmed
+pub fn colorize(instr: &str) -> String {
+    let (instr, comment) = match instr.split_once(" ; ") {
+        Some((instr, comment)) => (instr, Some(comment)),
+        None => (instr, None),
+    };
+    let (mnemonic, operands) = instr.split_once(' ').unwrap_or((instr, ""));
+    let mut line = paint(OPCODE, mnemonic);
+    for (i, operand) in operands.split(", ").filter(|o| !o.is_empty()).enumerate() {
+        line.push_str(if i == 0 { " " } else { ", " });
+        let is_register = operand.len() == 2 && operand.starts_with('R');
+        line.push_str(&paint(if is_register { REGISTER } else { NUMBER }, operand));
+    }
+    if let Some(comment) = comment {
+        line.push(' ');
+        line.push_str(&paint(COMMENT, &format!("; {}", comment)));
+    }
+    line
+}
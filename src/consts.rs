@@ -0,0 +1,37 @@
+// the ISA's numbers, shared by the interpreter, the assembler and the disassembler
+
+// the opcodes, the top four bits of an instruction
+pub const OP_BR: u16 = 0x0;
+pub const OP_ADD: u16 = 0x1;
+pub const OP_LD: u16 = 0x2;
+pub const OP_ST: u16 = 0x3;
+pub const OP_JSR: u16 = 0x4;
+pub const OP_AND: u16 = 0x5;
+pub const OP_LDR: u16 = 0x6;
+pub const OP_STR: u16 = 0x7;
+pub const OP_RTI: u16 = 0x8;
+pub const OP_NOT: u16 = 0x9;
+pub const OP_LDI: u16 = 0xA;
+pub const OP_STI: u16 = 0xB;
+pub const OP_JMP: u16 = 0xC;
+pub const OP_RES: u16 = 0xD;
+pub const OP_LEA: u16 = 0xE;
+pub const OP_TRAP: u16 = 0xF;
+
+// the standard trap vectors
+pub const TRAP_GETC: u16 = 0x20;
+pub const TRAP_OUT: u16 = 0x21;
+pub const TRAP_PUTS: u16 = 0x22;
+pub const TRAP_IN: u16 = 0x23;
+pub const TRAP_PUTSP: u16 = 0x24;
+pub const TRAP_HALT: u16 = 0x25;
+// the non-standard trap that dumps the registers, when enabled
+pub const TRAP_DEBUG: u16 = 0x26;
+// the non-standard trap that loads the cycles left under the cycle limit
+// into R0 (low word) and R1 (high word), when enabled
+pub const TRAP_BUDGET: u16 = 0x27;
+
+// the interrupt vector table, a device's handler address is at INT_TABLE + its vector
+pub const INT_TABLE: u16 = 0x0100;
+// the keyboard's interrupt vector
+pub const INT_KEYBOARD: u16 = 0x80;
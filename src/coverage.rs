@@ -0,0 +1,87 @@
+use std::io;
+use std::io::Write;
+
+// which words of the loaded code regions have been executed,
+// one bit per word of each region
+
+struct Region {
+    origin: u16,
+    len: usize,
+    bits: Vec<u64>,
+}
+
+pub struct Coverage {
+    regions: Vec<Region>,
+}
+
+impl Coverage {
+    // track the regions given as (origin, word count)
+    pub fn new(regions: &[(u16, usize)]) -> Coverage {
+        let regions = regions
+            .iter()
+            .map(|&(origin, len)| Region { origin, len, bits: vec![0; len.div_ceil(64)] })
+            .collect();
+        Coverage { regions }
+    }
+
+    fn locate(&self, address: u16) -> Option<(usize, usize)> {
+        self.regions.iter().enumerate().find_map(|(r, region)| {
+            let i = address.wrapping_sub(region.origin) as usize;
+            (address >= region.origin && i < region.len).then_some((r, i))
+        })
+    }
+
+    // mark the word at address as executed, addresses outside the regions are ignored
+    pub fn mark(&mut self, address: u16) {
+        if let Some((r, i)) = self.locate(address) {
+            self.regions[r].bits[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    pub fn is_executed(&self, address: u16) -> bool {
+        self.locate(address)
+            .is_some_and(|(r, i)| self.regions[r].bits[i / 64] & (1 << (i % 64)) != 0)
+    }
+
+    // number of words tracked
+    pub fn total(&self) -> usize {
+        self.regions.iter().map(|r| r.len).sum()
+    }
+
+    // number of tracked words executed at least once
+    pub fn executed(&self) -> usize {
+        self.regions
+            .iter()
+            .map(|r| r.bits.iter().map(|b| b.count_ones() as usize).sum::<usize>())
+            .sum()
+    }
+
+    // the tracked addresses never executed, in order
+    pub fn unexecuted(&self) -> Vec<u16> {
+        self.regions
+            .iter()
+            .flat_map(|r| (0..r.len).map(move |i| r.origin.wrapping_add(i as u16)))
+            .filter(|&a| !self.is_executed(a))
+            .collect()
+    }
+
+    // print the totals and the never-executed addresses, runs of them as ranges
+    pub fn report(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "executed {} of {} code words", self.executed(), self.total())?;
+        let missed = self.unexecuted();
+        let mut i = 0;
+        while i < missed.len() {
+            let start = missed[i];
+            while i + 1 < missed.len() && missed[i + 1] == missed[i].wrapping_add(1) {
+                i += 1;
+            }
+            if missed[i] == start {
+                writeln!(out, "  never executed: x{:04X}", start)?;
+            } else {
+                writeln!(out, "  never executed: x{:04X}-x{:04X}", start, missed[i])?;
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,502 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::io::{BufRead, Write};
+use crate::assembler::{assemble_one, parse_number, unescape};
+use crate::consts::OP_TRAP;
+use crate::disasm::{disassemble, dump_vectors};
+use crate::memmap::memory_map;
+use crate::symbols::Symbols;
+use crate::vm::RegisterType::{COND, PC};
+use crate::vm::{Flags, REGISTER_NAMES, REG_COUNT, VM};
+
+const HELP: &str = "\
+s [N] [-v]        step one or N instructions, stopping early at a breakpoint or
+                  HALT, -v shows the PC after every step
+rs                step back one instruction, output and input aren't undone
+c                 continue until a breakpoint, HALT or a likely hang
+b [ADDR]          set a breakpoint at ADDR, or list the breakpoints
+d ADDR            delete the breakpoint at ADDR
+rwatch [REG]      stop at the next write of REG, or list the watched registers
+rclear [REG]      stop watching REG, or every register
+r                 show the registers
+x ADDR [COUNT]    examine COUNT words of memory from ADDR, which can be a
+                  register plus or minus offsets, e.g. x R6 4 or x PC-2 5
+                  for b, d and x, ADDR can also be a label from --syms, e.g.
+                  b MAIN or x ARRAY+2 10
+find VAL          list the addresses holding VAL in all of memory
+find \"TEXT\"       list where TEXT is stored, a character per word or packed two
+                  to a word as PUTSP prints it
+set REG = VAL     write a register (R0-R7, SP, RET, PC, COND)
+cond              show the condition codes
+vectors           show the installed trap and interrupt vectors
+map               show where code, data and the vector tables are
+set cond N|Z|P    set the condition codes to exactly one flag
+set mem ADDR = VAL
+                  write a memory word
+asm ADDR INSTR    assemble INSTR at ADDR and write it there, e.g.
+                  asm x3010 ADD R0, R0, #1, PC-relative operands are numbers
+set step-into-traps on|off
+                  whether s follows a TRAP into its handler, on by default
+q                 quit
+";
+
+// steps rs can undo
+const HISTORY_DEPTH: usize = 100_000;
+
+// matches find lists before it only counts
+const FIND_LIMIT: usize = 20;
+
+// find a register index by name, SP and RET are aliases of R6 and R7
+pub fn register_index(name: &str) -> Option<u16> {
+    match name.to_ascii_uppercase().as_str() {
+        "SP" => Some(6),
+        "RET" => Some(7),
+        "PC" => Some(8),
+        "COND" => Some(9),
+        upper => upper
+            .strip_prefix('R')
+            .and_then(|n| n.parse::<u16>().ok())
+            .filter(|&i| i < 8),
+    }
+}
+
+// the COND bit patterns, exactly one is set at any time
+const FLAGS: [(&str, u16); 3] = [("N", Flags::N), ("Z", Flags::Z), ("P", Flags::P)];
+
+// a COND value from N, Z or P, or from its bit pattern
+fn parse_cond(token: &str) -> Result<u16, String> {
+    let named = FLAGS.iter().find(|(n, _)| n.eq_ignore_ascii_case(token)).map(|(_, v)| *v);
+    match named.or_else(|| parse_word(token).ok()) {
+        Some(v) if FLAGS.iter().any(|(_, f)| *f == v) => Ok(v),
+        _ => Err(format!("invalid condition '{}', expected exactly one of N, Z or P", token)),
+    }
+}
+
+// a 16 bit value written as x3000, 0x3000, #12 or 12
+fn parse_word(token: &str) -> Result<u16, String> {
+    match parse_number(token) {
+        Some(v) if (-0x8000..=0xffff).contains(&v) => Ok(v as u16),
+        _ => Err(format!("invalid value '{}'", token)),
+    }
+}
+
+// an address written as a number or a register, plus or minus more of
+// them, e.g. x3000, R0, PC-2 or R6+x10. the sum wraps like the VM's arithmetic
+fn parse_address(vm: &VM, symbols: &Symbols, expr: &str) -> Result<u16, String> {
+    let invalid = || format!("invalid address '{}', expected e.g. x3000, R0, PC-2 or a label", expr);
+    // a known label wins over a number, e.g. a label named xAB
+    let term = |t: &str| match (register_index(t), symbols.get(t)) {
+        (Some(i), _) => Ok(vm.read_reg_by_index(i)),
+        (None, Some(&address)) => Ok(address),
+        (None, None) => parse_word(t).map_err(|_| invalid()),
+    };
+    let mut address = 0u16;
+    let mut sign = 1;
+    let mut start = 0;
+    let bytes = expr.as_bytes();
+    for i in 0..=bytes.len() {
+        // a sign right after '#' belongs to the number
+        let operator = i < bytes.len() && matches!(bytes[i], b'+' | b'-') && i > 0 && bytes[i - 1] != b'#';
+        if i == bytes.len() || operator {
+            let value = term(expr[start..i].trim())?;
+            address = if sign > 0 { address.wrapping_add(value) } else { address.wrapping_sub(value) };
+            if i < bytes.len() {
+                sign = if bytes[i] == b'+' { 1 } else { -1 };
+            }
+            start = i + 1;
+        }
+    }
+    Ok(address)
+}
+
+// a command fails either on the user's input or on the output stream
+enum CmdError {
+    User(String),
+    Io(io::Error),
+}
+
+impl From<String> for CmdError {
+    fn from(e: String) -> Self {
+        CmdError::User(e)
+    }
+}
+
+impl From<io::Error> for CmdError {
+    fn from(e: io::Error) -> Self {
+        CmdError::Io(e)
+    }
+}
+
+type CmdResult = Result<(), CmdError>;
+
+// an interactive debugger driving a VM
+pub struct Debugger {
+    pub vm: VM,
+    breakpoints: BTreeSet<u16>,
+    // registers seen the last time a backward branch was taken, by branch address
+    spin: Option<(u16, Vec<u16>)>,
+    // when off, s runs a TRAP handler to its return as one step
+    step_into_traps: bool,
+    // labels commands accept in place of an address
+    symbols: Symbols,
+    // running into unwritten memory is reported once
+    warned_uninitialized: bool,
+}
+
+impl Debugger {
+    pub fn new(mut vm: VM) -> Debugger {
+        vm.set_history(HISTORY_DEPTH);
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            spin: None,
+            step_into_traps: true,
+            symbols: Symbols::new(),
+            warned_uninitialized: false,
+        }
+    }
+
+    // let commands take these labels for addresses
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<u16> {
+        &self.breakpoints
+    }
+
+    // read commands until quit or the input ends
+    pub fn repl(&mut self, input: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<()> {
+        let mut line = String::new();
+        loop {
+            write!(out, "(lc3) ")?;
+            out.flush()?;
+            line.clear();
+            if input.read_line(&mut line)? == 0 || !self.command(&line, out)? {
+                return Ok(());
+            }
+        }
+    }
+
+    // execute a single command line, returns false when the user quits
+    pub fn command(&mut self, line: &str, out: &mut dyn Write) -> io::Result<bool> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let result: CmdResult = match args.as_slice() {
+            // the text may hold spaces, so it's taken from the line rather than the words
+            ["find", ..] => self.find(line.trim()["find".len()..].trim(), out),
+            ["asm", addr, _, ..] => {
+                let text = line.trim()["asm".len()..].trim_start()[addr.len()..].trim();
+                self.patch(addr, text, out)
+            }
+            [] => Ok(()),
+            ["q"] | ["quit"] => return Ok(false),
+            ["h"] | ["help"] => write!(out, "{}", HELP).map_err(CmdError::from),
+            ["s"] | ["step"] => self.step(out),
+            ["s", n] | ["step", n] => self.step_many(n, false, out),
+            ["s", n, "-v"] | ["step", n, "-v"] => self.step_many(n, true, out),
+            ["rs"] => self.step_back(out),
+            ["c"] | ["continue"] => self.cont(out),
+            ["b"] | ["break"] => {
+                for b in &self.breakpoints {
+                    writeln!(out, "breakpoint at x{:04X}", b)?;
+                }
+                Ok(())
+            }
+            ["b", addr] | ["break", addr] => self.set_breakpoint(addr),
+            ["d", addr] | ["delete", addr] => self.delete_breakpoint(addr),
+            ["rwatch"] => {
+                for i in self.vm.register_watches() {
+                    writeln!(out, "watching {}", REGISTER_NAMES[i as usize])?;
+                }
+                Ok(())
+            }
+            ["rwatch", reg] => self.watch_register(reg, true),
+            ["rclear"] => {
+                for i in 0..REG_COUNT as u16 {
+                    self.vm.watch_register(i, false);
+                }
+                Ok(())
+            }
+            ["rclear", reg] => self.watch_register(reg, false),
+            ["cond"] => self.show_cond(out),
+            ["vectors"] => dump_vectors(&self.vm, out).map_err(CmdError::from),
+            ["map"] => memory_map(&self.vm, &self.symbols, out).map_err(CmdError::from),
+            ["r"] | ["regs"] => self.vm.dump_registers(out).map_err(CmdError::from),
+            ["x", addr] => self.examine(addr, "1", out),
+            ["x", addr, count] => self.examine(addr, count, out),
+            ["set", "mem", addr, "=", val] | ["set", "mem", addr, val] => self.set_memory(addr, val),
+            ["set", "step-into-traps", on] => self.set_step_into_traps(on),
+            ["set", reg, "=", val] | ["set", reg, val] => self.set_register(reg, val),
+            _ => Err(format!("unknown command '{}', try 'help'", line.trim()).into()),
+        };
+        match result {
+            Ok(()) => Ok(true),
+            Err(CmdError::User(e)) => writeln!(out, "{}", e).map(|_| true),
+            Err(CmdError::Io(e)) => Err(e),
+        }
+    }
+
+    fn check_running(&self) -> CmdResult {
+        match self.vm.halt_reason() {
+            None => Ok(()),
+            Some(reason) => Err(format!("program stopped: {}", reason).into()),
+        }
+    }
+
+    fn report_stop(&self, out: &mut dyn Write) -> CmdResult {
+        match self.vm.halt_reason() {
+            Some(reason) => writeln!(out, "program stopped: {}", reason)?,
+            None => writeln!(out, "PC = x{:04X}", self.vm.read_reg(PC))?,
+        }
+        Ok(())
+    }
+
+    fn set_breakpoint(&mut self, addr: &str) -> CmdResult {
+        self.breakpoints.insert(parse_address(&self.vm, &self.symbols, addr)?);
+        Ok(())
+    }
+
+    fn delete_breakpoint(&mut self, addr: &str) -> CmdResult {
+        let a = parse_address(&self.vm, &self.symbols, addr)?;
+        if !self.breakpoints.remove(&a) {
+            return Err(format!("no breakpoint at x{:04X}", a).into());
+        }
+        Ok(())
+    }
+
+    fn set_memory(&mut self, addr: &str, val: &str) -> CmdResult {
+        let a = parse_word(addr)?;
+        self.vm.poke(a, parse_word(val)?);
+        Ok(())
+    }
+
+    // assemble an instruction into memory, leaving memory alone if it doesn't assemble
+    fn patch(&mut self, addr: &str, text: &str, out: &mut dyn Write) -> CmdResult {
+        let a = parse_address(&self.vm, &self.symbols, addr)?;
+        let word = assemble_one(a, text)?;
+        let old = self.vm.peek(a);
+        self.vm.poke(a, word);
+        writeln!(out, "x{:04X}: x{:04X} -> x{:04X}  {}", a, old, word, disassemble(word, a))?;
+        Ok(())
+    }
+
+    fn set_register(&mut self, reg: &str, val: &str) -> CmdResult {
+        let i = register_index(reg).ok_or_else(|| format!("unknown register '{}'", reg))?;
+        let v = if i == 9 { parse_cond(val)? } else { parse_word(val)? };
+        self.vm.write_reg_by_index(i, v);
+        Ok(())
+    }
+
+    fn watch_register(&mut self, reg: &str, on: bool) -> CmdResult {
+        let i = register_index(reg).ok_or_else(|| format!("unknown register '{}'", reg))?;
+        self.vm.watch_register(i, on);
+        Ok(())
+    }
+
+    // report a watched register written by the instruction at pc
+    fn register_written(&mut self, pc: u16, out: &mut dyn Write) -> io::Result<bool> {
+        let Some(w) = self.vm.take_register_write() else {
+            return Ok(false);
+        };
+        let name = REGISTER_NAMES[w.index as usize];
+        writeln!(out, "{} written at x{:04X}: x{:04X} -> x{:04X}", name, pc, w.old, w.new)?;
+        Ok(true)
+    }
+
+    fn set_step_into_traps(&mut self, on: &str) -> CmdResult {
+        self.step_into_traps = match on {
+            "on" => true,
+            "off" => false,
+            _ => return Err(format!("expected on or off, not '{}'", on).into()),
+        };
+        Ok(())
+    }
+
+    fn show_cond(&self, out: &mut dyn Write) -> CmdResult {
+        let cond = self.vm.read_reg(COND);
+        let mut flags: String = FLAGS.iter().map(|(n, f)| if cond & f != 0 { *n } else { "-" }).collect();
+        if self.vm.overflow_tracking() {
+            flags.push_str(if self.vm.overflow() { " V" } else { " -" });
+        }
+        writeln!(out, "{} (x{:04X})", flags, cond)?;
+        Ok(())
+    }
+
+    fn step(&mut self, out: &mut dyn Write) -> CmdResult {
+        self.check_running()?;
+        self.step_one(out)?;
+        self.report_stop(out)
+    }
+
+    // step count instructions, stopping early at a breakpoint, a watched
+    // register write or a halt. verbose shows the state after every step
+    fn step_many(&mut self, count: &str, verbose: bool, out: &mut dyn Write) -> CmdResult {
+        let count = count.parse::<u64>().ok().filter(|&n| n > 0).ok_or_else(|| format!("invalid step count '{}'", count))?;
+        self.check_running()?;
+        for i in 0..count {
+            let pc = self.vm.read_reg(PC);
+            if i > 0 && self.breakpoints.contains(&pc) {
+                writeln!(out, "breakpoint at x{:04X} after {} of {} steps", pc, i, count)?;
+                break;
+            }
+            let stopped = self.step_one(out)?;
+            if !self.vm.running() || (stopped && i + 1 < count) {
+                writeln!(out, "stopped after {} of {} steps", i + 1, count)?;
+                break;
+            }
+            if verbose && i + 1 < count {
+                self.report_stop(out)?;
+            }
+        }
+        self.report_stop(out)
+    }
+
+    // execute one instruction, or a whole trap handler when not stepping
+    // into traps. true when it stopped early at a watch or breakpoint
+    fn step_one(&mut self, out: &mut dyn Write) -> Result<bool, CmdError> {
+        let pc = self.vm.read_reg(PC);
+        let trap = self.vm.fetch(pc) >> 12 == OP_TRAP;
+        // a write from the debugger itself isn't the program's
+        self.vm.take_register_write();
+        self.vm.step();
+        if self.register_written(pc, out)? || self.uninitialized_fetch(out)? {
+            return Ok(true);
+        }
+        if trap && !self.step_into_traps {
+            // like stepping over a call, run the handler until it returns
+            // behind the TRAP, stopping early at a breakpoint inside it
+            let ret = pc.wrapping_add(1);
+            while self.vm.running() {
+                let at = self.vm.read_reg(PC);
+                if at == ret {
+                    break;
+                }
+                if self.breakpoints.contains(&at) {
+                    writeln!(out, "breakpoint at x{:04X}", at)?;
+                    return Ok(true);
+                }
+                self.vm.step();
+                if self.register_written(at, out)? || self.uninitialized_fetch(out)? {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    // warn, once, when the next instruction is fetched from a word nothing
+    // ever wrote: a zero word is a BR that never branches, so the program
+    // slides silently through memory. true when it warned
+    fn uninitialized_fetch(&mut self, out: &mut dyn Write) -> io::Result<bool> {
+        let pc = self.vm.read_reg(PC);
+        if self.warned_uninitialized || !self.vm.running() || self.vm.was_written(pc) {
+            return Ok(false);
+        }
+        self.warned_uninitialized = true;
+        writeln!(out, "warning: executing uninitialized memory at x{:04X}, likely a missing HALT or a bad jump", pc)?;
+        Ok(true)
+    }
+
+    fn step_back(&mut self, out: &mut dyn Write) -> CmdResult {
+        if !self.vm.step_back() {
+            return Err("no earlier step recorded".to_string().into());
+        }
+        self.report_stop(out)
+    }
+
+    // run until the next breakpoint or until the program stops
+    fn cont(&mut self, out: &mut dyn Write) -> CmdResult {
+        self.check_running()?;
+        self.spin = None;
+        self.vm.take_register_write();
+        loop {
+            let from = self.vm.read_reg(PC);
+            self.vm.step();
+            if self.register_written(from, out)? || !self.vm.running() || self.uninitialized_fetch(out)? {
+                break;
+            }
+            let pc = self.vm.read_reg(PC);
+            if self.breakpoints.contains(&pc) {
+                writeln!(out, "breakpoint at x{:04X}", pc)?;
+                break;
+            }
+            if self.spinning(from, pc) {
+                writeln!(out, "likely hang: x{:04X}-x{:04X} loops without changing any register", pc, from)?;
+                break;
+            }
+        }
+        self.report_stop(out)
+    }
+
+    // a heuristic for a program stuck in a loop, like subtracting a zero divisor
+    // until the dividend goes negative: the same backward branch is taken twice
+    // in a row with identical registers. a loop that also counts iterations
+    // changes a register and isn't caught
+    fn spinning(&mut self, from: u16, to: u16) -> bool {
+        let instr = self.vm.fetch(from);
+        if instr >> 12 != 0 || to > from {
+            return false;
+        }
+        let regs = self.vm.registers().to_vec();
+        match &self.spin {
+            Some((branch, seen)) if *branch == from && *seen == regs => true,
+            _ => {
+                self.spin = Some((from, regs));
+                false
+            }
+        }
+    }
+
+    // list the addresses where a value or a string starts, a string is
+    // searched unpacked and packed low byte first
+    fn find(&self, pattern: &str, out: &mut dyn Write) -> CmdResult {
+        let mut found: Vec<(u16, &str)> = Vec::new();
+        if pattern.len() >= 2 && pattern.starts_with('"') && pattern.ends_with('"') {
+            let text = unescape(&pattern[1..pattern.len() - 1])?;
+            if text.is_empty() || !text.is_ascii() {
+                return Err("expected a non-empty ASCII string".to_string().into());
+            }
+            let bytes = text.as_bytes();
+            let unpacked: Vec<u16> = bytes.iter().map(|&b| b as u16).collect();
+            let packed: Vec<u16> = bytes.chunks(2).map(|p| p[0] as u16 | (*p.get(1).unwrap_or(&0) as u16) << 8).collect();
+            let odd = bytes.len() % 2 == 1;
+            for a in 0..=0xFFFFu16 {
+                let word = |i: usize| self.vm.peek(a.wrapping_add(i as u16));
+                if unpacked.iter().enumerate().all(|(i, &c)| word(i) == c) {
+                    found.push((a, " unpacked"));
+                }
+                // a single character packs to the word it unpacks to
+                if bytes.len() > 1 && packed.iter().enumerate().all(|(i, &w)| {
+                    // an odd length leaves the high byte of the last word free
+                    let mask = if odd && i == packed.len() - 1 { 0xFF } else { 0xFFFF };
+                    word(i) & mask == w
+                }) {
+                    found.push((a, " packed"));
+                }
+            }
+        } else {
+            let value = parse_word(pattern)?;
+            found.extend((0..=0xFFFFu16).filter(|&a| self.vm.peek(a) == value).map(|a| (a, "")));
+        }
+        for (a, form) in found.iter().take(FIND_LIMIT) {
+            writeln!(out, "x{:04X}{}", a, form)?;
+        }
+        match found.len() {
+            0 => writeln!(out, "not found")?,
+            1 => writeln!(out, "1 match")?,
+            n if n > FIND_LIMIT => writeln!(out, "{} matches, showing the first {}", n, FIND_LIMIT)?,
+            n => writeln!(out, "{} matches", n)?,
+        }
+        Ok(())
+    }
+
+    fn examine(&self, addr: &str, count: &str, out: &mut dyn Write) -> CmdResult {
+        let start = parse_address(&self.vm, &self.symbols, addr)?;
+        let count = parse_word(count)?;
+        for i in 0..count {
+            let a = start.wrapping_add(i);
+            writeln!(out, "x{:04X}: x{:04X}", a, self.vm.peek(a))?;
+        }
+        Ok(())
+    }
+}
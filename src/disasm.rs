@@ -0,0 +1,159 @@
+use std::io;
+use std::io::Write;
+use crate::consts::{TRAP_GETC, TRAP_HALT, TRAP_IN, TRAP_OUT, TRAP_PUTS, TRAP_PUTSP};
+use crate::vm::{decode, get_op, Flags, Op, VM};
+
+// the conventional alias of a standard trap vector
+pub fn trap_name(vector: u16) -> Option<&'static str> {
+    match vector {
+        TRAP_GETC => Some("GETC"),
+        TRAP_OUT => Some("OUT"),
+        TRAP_PUTS => Some("PUTS"),
+        TRAP_IN => Some("IN"),
+        TRAP_PUTSP => Some("PUTSP"),
+        TRAP_HALT => Some("HALT"),
+        _ => None,
+    }
+}
+
+// render an instruction as assembly, address is where it's placed so
+// PC-relative operands can be shown as absolute target addresses
+pub fn disassemble(instr: u16, address: u16) -> String {
+    // immediates are extended as the executor does and shown signed, as written
+    let d = decode(instr);
+    let next = address.wrapping_add(1);
+    let pc_offset9 = next.wrapping_add(d.pc_offset9 as u16);
+    match d.op {
+        Op::BR => {
+            if d.nzp == 0 {
+                return "NOP".to_string();
+            }
+            let nzp = Flags::from_bits(d.nzp);
+            let mut flags = String::new();
+            if nzp.n {
+                flags.push('n');
+            }
+            if nzp.z {
+                flags.push('z');
+            }
+            if nzp.p {
+                flags.push('p');
+            }
+            format!("BR{} x{:04X}", flags, pc_offset9)
+        }
+        op @ (Op::ADD | Op::AND) => {
+            let name = if op == Op::ADD { "ADD" } else { "AND" };
+            if d.imm_mode {
+                format!("{} R{}, R{}, #{}", name, d.dr, d.sr1, d.imm5)
+            } else {
+                format!("{} R{}, R{}, R{}", name, d.dr, d.sr1, d.sr2)
+            }
+        }
+        Op::LD => format!("LD R{}, x{:04X}", d.dr, pc_offset9),
+        Op::ST => format!("ST R{}, x{:04X}", d.dr, pc_offset9),
+        Op::LDI => format!("LDI R{}, x{:04X}", d.dr, pc_offset9),
+        Op::STI => format!("STI R{}, x{:04X}", d.dr, pc_offset9),
+        Op::LEA => format!("LEA R{}, x{:04X}", d.dr, pc_offset9),
+        Op::JSR => {
+            if d.jsr_offset {
+                format!("JSR x{:04X}", next.wrapping_add(d.pc_offset11 as u16))
+            } else {
+                format!("JSRR R{}", d.base_r)
+            }
+        }
+        Op::LDR => format!("LDR R{}, R{}, #{}", d.dr, d.base_r, d.offset6),
+        Op::STR => format!("STR R{}, R{}, #{}", d.dr, d.base_r, d.offset6),
+        Op::NOT => format!("NOT R{}, R{}", d.dr, d.sr1),
+        Op::JMP if d.base_r == 7 => "RET".to_string(),
+        Op::JMP => format!("JMP R{}", d.base_r),
+        Op::RTI => "RTI".to_string(),
+        Op::TRAP => match trap_name(d.trap_vect) {
+            Some(name) => format!("TRAP x{:02X} ; {}", d.trap_vect, name),
+            None => format!("TRAP x{:02X}", d.trap_vect),
+        },
+        Op::RES | Op::Unknown => format!(".FILL x{:04X}", instr),
+    }
+}
+
+// print the installed trap (0x0000-0x00FF) and interrupt (0x0100-0x01FF)
+// vector table entries with the first instruction of each handler
+pub fn dump_vectors(vm: &VM, out: &mut dyn Write) -> io::Result<()> {
+    for (title, table) in [("trap vectors", 0x0000u16), ("interrupt vectors", 0x0100)] {
+        writeln!(out, "{}:", title)?;
+        for vector in table..table + 0x100 {
+            let target = vm.peek(vector);
+            if target != 0 {
+                let instr = vm.fetch(target);
+                writeln!(out, "  x{:02X} -> x{:04X}  {}", vector, target, disassemble(instr, target))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// the assembly forms of an opcode and the bit layout of each, opcode bits first
+pub fn encodings(op: &Op) -> &'static [(&'static str, &'static str)] {
+    match op {
+        Op::BR => &[("BR[n][z][p] LABEL", "0000 n z p PCoffset9")],
+        Op::ADD => &[("ADD DR, SR1, SR2", "0001 DR SR1 0 00 SR2"), ("ADD DR, SR1, imm5", "0001 DR SR1 1 imm5")],
+        Op::LD => &[("LD DR, LABEL", "0010 DR PCoffset9")],
+        Op::ST => &[("ST SR, LABEL", "0011 SR PCoffset9")],
+        Op::JSR => &[("JSR LABEL", "0100 1 PCoffset11"), ("JSRR BaseR", "0100 0 00 BaseR 000000")],
+        Op::AND => &[("AND DR, SR1, SR2", "0101 DR SR1 0 00 SR2"), ("AND DR, SR1, imm5", "0101 DR SR1 1 imm5")],
+        Op::LDR => &[("LDR DR, BaseR, offset6", "0110 DR BaseR offset6")],
+        Op::STR => &[("STR SR, BaseR, offset6", "0111 SR BaseR offset6")],
+        Op::RTI => &[("RTI", "1000 000000000000")],
+        Op::NOT => &[("NOT DR, SR", "1001 DR SR 111111")],
+        Op::LDI => &[("LDI DR, LABEL", "1010 DR PCoffset9")],
+        Op::STI => &[("STI SR, LABEL", "1011 SR PCoffset9")],
+        Op::JMP => &[("JMP BaseR", "1100 000 BaseR 000000"), ("RET", "1100 000 111 000000")],
+        Op::RES => &[("(reserved)", "1101 ????????????")],
+        Op::LEA => &[("LEA DR, LABEL", "1110 DR PCoffset9")],
+        Op::TRAP => &[("TRAP trapvect8", "1111 0000 trapvect8")],
+        Op::Unknown => &[],
+    }
+}
+
+// print every opcode with its value, its forms and their bit layouts.
+// DR, SR and BaseR are 3 bit register numbers, the immediates and offsets
+// are sign-extended, trapvect8 is zero-extended
+pub fn opcode_table(out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "{:<4} {:<6} {:<24} LAYOUT", "OP", "BITS", "FORM")?;
+    for opcode in 0..16u16 {
+        for (form, layout) in encodings(&get_op(opcode)) {
+            writeln!(out, "x{:<3X} {:04b}   {:<24} {}", opcode, opcode, form, layout)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn negative_immediates_are_signed() {
+        assert_eq!(disassemble(0x127D, 0x3000), "ADD R1, R1, #-3");
+        assert_eq!(disassemble(0x5030, 0x3000), "AND R0, R0, #-16");
+        assert_eq!(disassemble(0x6FBF, 0x3000), "LDR R7, R6, #-1");
+    }
+
+    #[test]
+    fn negative_offsets_show_the_target() {
+        // LD R0, #-2 and BRnzp #-1 at x3005
+        assert_eq!(disassemble(0x21FE, 0x3005), "LD R0, x3004");
+        assert_eq!(disassemble(0x0FFF, 0x3005), "BRnzp x3005");
+        // JSR #-1024, and LD #-2 at x0000 wrapping below zero
+        assert_eq!(disassemble(0x4C00, 0x3000), "JSR x2C01");
+        assert_eq!(disassemble(0x21FE, 0x0000), "LD R0, xFFFF");
+    }
+
+    #[test]
+    fn assembled_immediates_round_trip() {
+        let program = assemble(".ORIG x3000\nADD R0, R0, #-1\nLOOP BRnzp LOOP\n.END\n").unwrap();
+        let words = &program.sections[0].words;
+        assert_eq!(disassemble(words[0], 0x3000), "ADD R0, R0, #-1");
+        assert_eq!(disassemble(words[1], 0x3001), "BRnzp x3001");
+    }
+}
@@ -0,0 +1,199 @@
+// a small API over the VM for hosts without a terminal or blocking I/O, such
+// as a browser build on wasm32-unknown-unknown. everything crosses as plain
+// numbers, bytes and strings so a #[wasm_bindgen] wrapper can pass it on
+// unchanged. the host hands keys in, by a callback or ahead of time, and
+// collects the output when it likes
+//
+// a program asking for a key that isn't there yet doesn't stop: the step is
+// undone, output included, and tried again on the next call once the host
+// has a key
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use crate::runner::Capture;
+use crate::vm::{HaltReason, VM};
+
+// asked for the next key whenever the program reads one and none are
+// queued, None when the user hasn't typed anything
+pub type KeyCallback = Box<dyn FnMut() -> Option<u8> + Send>;
+
+// where a Machine stands after a call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    // the program wants a key, push one or let the callback return one
+    WaitingForInput,
+    Halted,
+    // stopped on anything but HALT, error() says why
+    Error,
+}
+
+#[derive(Default)]
+struct Keys {
+    queue: VecDeque<u8>,
+    callback: Option<KeyCallback>,
+}
+
+// the VM's keyboard, reading past the last key ends the stream
+#[derive(Clone, Default)]
+struct Keyboard(Arc<Mutex<Keys>>);
+
+impl Read for Keyboard {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut keys = self.0.lock().unwrap();
+        let key = match keys.queue.pop_front() {
+            Some(key) => Some(key),
+            None => keys.callback.as_mut().and_then(|next| next()),
+        };
+        match (key, buf.first_mut()) {
+            (Some(key), Some(byte)) => {
+                *byte = key;
+                Ok(1)
+            }
+            _ => Ok(0),
+        }
+    }
+}
+
+pub struct Machine {
+    vm: VM,
+    keyboard: Keyboard,
+    output: Capture,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Machine::new()
+    }
+}
+
+impl Machine {
+    pub fn new() -> Machine {
+        let keyboard = Keyboard::default();
+        let output = Capture::default();
+        let mut vm = VM::with_io(Box::new(keyboard.clone()), Box::new(output.clone()));
+        // one step of history is enough to retry a read that found no key
+        vm.set_history(1);
+        Machine { vm, keyboard, output }
+    }
+
+    // take keys from callback once the pushed ones run out
+    pub fn set_keyboard(&mut self, callback: KeyCallback) {
+        self.keyboard.0.lock().unwrap().callback = Some(callback);
+    }
+
+    // queue keys for the program
+    pub fn push_input(&mut self, text: &str) {
+        self.keyboard.0.lock().unwrap().queue.extend(text.bytes());
+    }
+
+    // load an image from the bytes of an .obj file
+    pub fn load(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.vm.load_bytes(bytes).map_err(|e| e.to_string())
+    }
+
+    // execute one instruction
+    pub fn step(&mut self) -> State {
+        if !self.vm.running() {
+            return self.state();
+        }
+        let written = self.output.len();
+        self.vm.step();
+        if self.vm.halt_reason() == Some(HaltReason::InputExhausted) {
+            self.vm.step_back();
+            self.output.truncate(written);
+            return State::WaitingForInput;
+        }
+        self.state()
+    }
+
+    // execute up to max_steps instructions, stopping early to wait for a key
+    // or when the program stops. a host calls this once per animation frame
+    pub fn run(&mut self, max_steps: u32) -> State {
+        for _ in 0..max_steps {
+            let state = self.step();
+            if state != State::Running {
+                return state;
+            }
+        }
+        self.state()
+    }
+
+    pub fn state(&self) -> State {
+        match self.vm.halt_reason() {
+            None => State::Running,
+            Some(HaltReason::Halt) => State::Halted,
+            Some(_) => State::Error,
+        }
+    }
+
+    // why the program stopped, when it stopped on an error
+    pub fn error(&self) -> Option<String> {
+        self.vm.halt_reason().filter(|&r| r != HaltReason::Halt).map(|r| r.to_string())
+    }
+
+    // R0-R7, PC and COND
+    pub fn registers(&self) -> Vec<u16> {
+        self.vm.registers().to_vec()
+    }
+
+    // count words of memory from start, without triggering the devices
+    pub fn memory(&self, start: u16, count: u16) -> Vec<u16> {
+        (0..count).map(|i| self.vm.peek(start.wrapping_add(i))).collect()
+    }
+
+    // the output since the last call
+    pub fn take_output(&mut self) -> String {
+        let bytes = self.output.take();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    // the VM itself, for settings this API doesn't cover
+    pub fn vm(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    // echo two keys, then halt
+    const ECHO: &str = ".ORIG x3000\nGETC\nOUT\nGETC\nOUT\nHALT\n.END\n";
+
+    fn machine(src: &str) -> Machine {
+        let mut machine = Machine::new();
+        machine.load(&assemble(src).unwrap().to_bytes()).unwrap();
+        machine
+    }
+
+    #[test]
+    fn machine_waits_for_keys_and_echoes_them() {
+        let mut machine = machine(ECHO);
+        assert_eq!(machine.run(100), State::WaitingForInput);
+        assert_eq!(machine.registers()[8], 0x3000);
+        machine.push_input("a");
+        // the retried GETC and the OUT, then the second GETC finds no key
+        assert_eq!(machine.step(), State::Running);
+        assert_eq!(machine.step(), State::Running);
+        assert_eq!(machine.take_output(), "a");
+        assert_eq!(machine.step(), State::WaitingForInput);
+        machine.push_input("b");
+        assert_eq!(machine.run(100), State::Halted);
+        assert_eq!(machine.take_output(), "b");
+        assert_eq!(machine.take_output(), "");
+        assert_eq!(machine.error(), None);
+    }
+
+    #[test]
+    fn machine_takes_keys_from_the_callback() {
+        let mut machine = machine(ECHO);
+        let mut keys = "xy".bytes();
+        machine.set_keyboard(Box::new(move || keys.next()));
+        assert_eq!(machine.run(100), State::Halted);
+        assert_eq!(machine.take_output(), "xy");
+    }
+}
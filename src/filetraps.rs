@@ -0,0 +1,205 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use crate::vm::RegisterType::{R0, R1, R2};
+use crate::vm::{MR_KBSR, VM};
+
+// non-standard traps giving a program host files, installed with install():
+//
+// TRAP x30 OPEN   R0 = path, a zero-terminated string like PUTS takes,
+//                 R1 = 0 read, 1 write (create or truncate), 2 append.
+//                 R0 = the handle
+// TRAP x31 READ   R0 = handle, R1 = buffer, R2 = most bytes to read, one per word.
+//                 R0 = the bytes read, 0 at the end of the file
+// TRAP x32 WRITE  R0 = handle, R1 = buffer, R2 = words to write, the low byte of each.
+//                 R0 = the bytes written
+// TRAP x33 CLOSE  R0 = handle. R0 = 0
+//
+// on any error R0 is xFFFF instead, COND is left alone. a buffer must end below
+// the device registers at xFE00 and stores into it respect write protection
+
+pub const TRAP_OPEN: u8 = 0x30;
+pub const TRAP_READ: u8 = 0x31;
+pub const TRAP_WRITE: u8 = 0x32;
+pub const TRAP_CLOSE: u8 = 0x33;
+// open files a program may hold at once
+pub const MAX_HANDLES: usize = 8;
+// longest path accepted, in words
+const MAX_PATH: usize = 255;
+const FAILED: u16 = 0xffff;
+
+type Handles = Arc<Mutex<Vec<Option<File>>>>;
+
+// the zero-terminated path at address, None if it's too long or runs into the devices
+fn read_path(vm: &VM, address: u16) -> Option<String> {
+    let mut path = String::new();
+    for i in 0..=MAX_PATH as u16 {
+        let a = address.checked_add(i).filter(|&a| a < MR_KBSR)?;
+        match vm.peek(a) {
+            0 => return Some(path),
+            c @ 1..=0x7f => path.push(c as u8 as char),
+            _ => return None,
+        }
+    }
+    None
+}
+
+// a count-word buffer at address that stays below the device registers
+fn buffer(address: u16, count: u16) -> Option<u16> {
+    (address as u32 + count as u32 <= MR_KBSR as u32).then_some(address)
+}
+
+fn open(vm: &VM, handles: &Handles) -> Option<u16> {
+    let path = read_path(vm, vm.read_reg(R0))?;
+    let mut options = OpenOptions::new();
+    match vm.read_reg(R1) {
+        0 => options.read(true),
+        1 => options.write(true).create(true).truncate(true),
+        2 => options.append(true).create(true),
+        _ => return None,
+    };
+    let mut handles = handles.lock().unwrap();
+    let free = match handles.iter().position(Option::is_none) {
+        Some(i) => i,
+        None if handles.len() < MAX_HANDLES => {
+            handles.push(None);
+            handles.len() - 1
+        }
+        None => return None,
+    };
+    handles[free] = Some(options.open(path).ok()?);
+    Some(free as u16)
+}
+
+fn read(vm: &mut VM, handles: &Handles) -> Option<u16> {
+    let (handle, count) = (vm.read_reg(R0) as usize, vm.read_reg(R2));
+    let start = buffer(vm.read_reg(R1), count)?;
+    let mut bytes = vec![0; count as usize];
+    let n = handles.lock().unwrap().get_mut(handle)?.as_mut()?.read(&mut bytes).ok()?;
+    for (i, b) in bytes[..n].iter().enumerate() {
+        vm.write_memory(start + i as u16, *b as u16);
+    }
+    Some(n as u16)
+}
+
+fn write(vm: &VM, handles: &Handles) -> Option<u16> {
+    let (handle, count) = (vm.read_reg(R0) as usize, vm.read_reg(R2));
+    let start = buffer(vm.read_reg(R1), count)?;
+    let bytes: Vec<u8> = (0..count).map(|i| vm.peek(start + i) as u8).collect();
+    handles.lock().unwrap().get_mut(handle)?.as_mut()?.write_all(&bytes).ok()?;
+    Some(count)
+}
+
+fn close(vm: &VM, handles: &Handles) -> Option<u16> {
+    handles.lock().unwrap().get_mut(vm.read_reg(R0) as usize)?.take()?;
+    Some(0)
+}
+
+// register the file traps on the VM, the handle table lives with them
+pub fn install(vm: &mut VM) {
+    let handles: Handles = Arc::new(Mutex::new(Vec::new()));
+    let h = handles.clone();
+    vm.register_trap(TRAP_OPEN, Box::new(move |vm| {
+        let r = open(vm, &h).unwrap_or(FAILED);
+        vm.write_reg(R0, r);
+    }));
+    let h = handles.clone();
+    vm.register_trap(TRAP_READ, Box::new(move |vm| {
+        let r = read(vm, &h).unwrap_or(FAILED);
+        vm.write_reg(R0, r);
+    }));
+    let h = handles.clone();
+    vm.register_trap(TRAP_WRITE, Box::new(move |vm| {
+        let r = write(vm, &h).unwrap_or(FAILED);
+        vm.write_reg(R0, r);
+    }));
+    vm.register_trap(TRAP_CLOSE, Box::new(move |vm| {
+        let r = close(vm, &handles).unwrap_or(FAILED);
+        vm.write_reg(R0, r);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::RegisterType::PC;
+
+    // a VM with the file traps, and a file in the temp dir holding bytes
+    fn setup(name: &str, bytes: &[u8]) -> (VM, String) {
+        let path = std::env::temp_dir().join(format!("lc3-filetraps-{}-{}.txt", std::process::id(), name));
+        std::fs::write(&path, bytes).unwrap();
+        let mut vm = VM::with_io(Box::new(std::io::empty()), Box::new(std::io::sink()));
+        install(&mut vm);
+        (vm, path.to_string_lossy().into_owned())
+    }
+
+    // execute TRAP vector at the PC with the registers given, R0 after it
+    fn trap(vm: &mut VM, vector: u8, r0: u16, r1: u16, r2: u16) -> u16 {
+        vm.write_reg(R0, r0);
+        vm.write_reg(R1, r1);
+        vm.write_reg(R2, r2);
+        vm.poke(vm.read_reg(PC), 0xF000 | vector as u16);
+        vm.step();
+        vm.read_reg(R0)
+    }
+
+    // poke the path at address with its terminator
+    fn poke_path(vm: &mut VM, address: u16, path: &str) {
+        for (i, c) in path.bytes().chain([0]).enumerate() {
+            vm.poke(address + i as u16, c as u16);
+        }
+    }
+
+    #[test]
+    fn ninth_open_fails_until_a_handle_is_closed() {
+        let (mut vm, path) = setup("handles", b"x");
+        poke_path(&mut vm, 0x4000, &path);
+        for handle in 0..MAX_HANDLES as u16 {
+            assert_eq!(trap(&mut vm, TRAP_OPEN, 0x4000, 0, 0), handle);
+        }
+        assert_eq!(trap(&mut vm, TRAP_OPEN, 0x4000, 0, 0), FAILED);
+        assert_eq!(trap(&mut vm, TRAP_CLOSE, 3, 0, 0), 0);
+        assert_eq!(trap(&mut vm, TRAP_OPEN, 0x4000, 0, 0), 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn read_into_the_device_registers_is_refused() {
+        let (mut vm, path) = setup("read", b"ab");
+        poke_path(&mut vm, 0x4000, &path);
+        let handle = trap(&mut vm, TRAP_OPEN, 0x4000, 0, 0);
+        assert_eq!(trap(&mut vm, TRAP_READ, handle, 0xFDFF, 2), FAILED);
+        assert_eq!(vm.peek(0xFDFF), 0);
+        // ending right below xFE00 fits
+        assert_eq!(trap(&mut vm, TRAP_READ, handle, 0xFDFF, 1), 1);
+        assert_eq!(vm.peek(0xFDFF), b'a' as u16);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn path_running_into_the_device_registers_is_refused() {
+        let (mut vm, path) = setup("unterminated", b"");
+        // the path fills the words up to xFE00 without a terminator
+        let start = MR_KBSR - path.len() as u16;
+        for (i, c) in path.bytes().enumerate() {
+            vm.poke(start + i as u16, c as u16);
+        }
+        assert_eq!(trap(&mut vm, TRAP_OPEN, start, 0, 0), FAILED);
+        // one word earlier there's room for the terminator
+        poke_path(&mut vm, start - 1, &path);
+        assert_eq!(trap(&mut vm, TRAP_OPEN, start - 1, 0, 0), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn closing_a_closed_handle_fails() {
+        let (mut vm, path) = setup("close", b"");
+        poke_path(&mut vm, 0x4000, &path);
+        let handle = trap(&mut vm, TRAP_OPEN, 0x4000, 0, 0);
+        assert_eq!(trap(&mut vm, TRAP_CLOSE, handle, 0, 0), 0);
+        assert_eq!(trap(&mut vm, TRAP_CLOSE, handle, 0, 0), FAILED);
+        // a handle that was never opened
+        assert_eq!(trap(&mut vm, TRAP_CLOSE, 5, 0, 0), FAILED);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
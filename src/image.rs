@@ -0,0 +1,326 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+use crate::link::{Object, OBJECT_MAGIC, OBJECT_VERSION};
+use crate::vm::MR_KBSR;
+
+// leads an object file holding more than one section, with the format
+// version after it as a word, followed by each section as its origin, its
+// word count and its words
+pub const SECTIONS_MAGIC: [u8; 4] = *b"LC3S";
+pub const SECTIONS_VERSION: u16 = 1;
+
+// a format's magic and version, written ahead of its contents
+pub(crate) fn header(magic: &[u8; 4], version: u16) -> Vec<u8> {
+    let mut bytes = magic.to_vec();
+    bytes.extend_from_slice(&version.to_be_bytes());
+    bytes
+}
+
+// the bytes after a format's magic and version. a plain .obj has no header
+// and can start with any word, so a file is only taken for another format
+// when both match: an image at x4C43 starting with x3353 spells "LC3S" and
+// is still read as a plain .obj
+pub(crate) fn strip_header<'a>(bytes: &'a [u8], magic: &[u8; 4], version: u16) -> Option<&'a [u8]> {
+    bytes.strip_prefix(magic)?.strip_prefix(&version.to_be_bytes())
+}
+
+// an object image: the origin followed by the words loaded from there
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub origin: u16,
+    pub words: Vec<u16>,
+}
+
+impl Image {
+    // parse big-endian .obj bytes, the first word is the origin
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Image> {
+        if bytes.len() < 2 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "image has no origin"));
+        }
+        let origin = (bytes[0] as u16) << 8 | bytes[1] as u16;
+        // a trailing odd byte is ignored
+        let image = Image { origin, words: words(&bytes[2..]) };
+        image.check_fits()?;
+        Ok(image)
+    }
+
+    // an error unless the words fit between the origin and the end of memory
+    pub fn check_fits(&self) -> io::Result<()> {
+        if self.end() > 0x10000 {
+            return Err(invalid(format!(
+                "image at x{:04X} has {} words and runs {} words past the end of memory",
+                self.origin,
+                self.words.len(),
+                self.end() - 0x10000
+            )));
+        }
+        Ok(())
+    }
+
+    // an error if any word would land in the device registers, where loading
+    // is unsupported: the words would be clobbered by, or drive, the devices
+    pub fn check_devices(&self) -> io::Result<()> {
+        if !self.words.is_empty() && self.end() > MR_KBSR as u32 {
+            return Err(invalid(format!(
+                "image at x{:04X}-x{:04X} would load into the device registers at xFE00-xFFFF",
+                self.origin,
+                self.end() - 1
+            )));
+        }
+        Ok(())
+    }
+
+    // encode as a single-section .obj file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(2 + self.words.len() * 2);
+        bytes.extend_from_slice(&self.origin.to_be_bytes());
+        for w in &self.words {
+            bytes.extend_from_slice(&w.to_be_bytes());
+        }
+        bytes
+    }
+
+    // one past the last loaded address
+    pub fn end(&self) -> u32 {
+        self.origin as u32 + self.words.len() as u32
+    }
+}
+
+// big-endian words
+fn words(bytes: &[u8]) -> Vec<u16> {
+    bytes.chunks_exact(2).map(|w| (w[0] as u16) << 8 | w[1] as u16).collect()
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// encode sections as an object file, a single section is written as a plain .obj
+pub fn sections_to_bytes(sections: &[Image]) -> Vec<u8> {
+    if let [image] = sections {
+        return image.to_bytes();
+    }
+    let mut bytes = header(&SECTIONS_MAGIC, SECTIONS_VERSION);
+    for image in sections {
+        bytes.extend_from_slice(&image.origin.to_be_bytes());
+        bytes.extend_from_slice(&(image.words.len() as u16).to_be_bytes());
+        for w in &image.words {
+            bytes.extend_from_slice(&w.to_be_bytes());
+        }
+    }
+    bytes
+}
+
+// parse a plain .obj or a multi-section object file. a linkable object is
+// accepted once it has nothing left to resolve
+pub fn sections_from_bytes(bytes: &[u8]) -> io::Result<Vec<Image>> {
+    if strip_header(bytes, &OBJECT_MAGIC, OBJECT_VERSION).is_some() {
+        let object = Object::from_bytes(bytes)?;
+        if let Some(r) = object.references.first() {
+            return Err(invalid(format!("unresolved external label '{}', link the object first", r.symbol)));
+        }
+        return Ok(object.sections);
+    }
+    let Some(mut rest) = strip_header(bytes, &SECTIONS_MAGIC, SECTIONS_VERSION) else {
+        return Ok(vec![Image::from_bytes(bytes)?]);
+    };
+    let mut sections: Vec<Image> = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < 4 {
+            return Err(invalid("truncated section header".to_string()));
+        }
+        let origin = (rest[0] as u16) << 8 | rest[1] as u16;
+        let count = ((rest[2] as usize) << 8 | rest[3] as usize) * 2;
+        if rest.len() < 4 + count {
+            return Err(invalid(format!("section at x{:04X} is truncated", origin)));
+        }
+        let image = Image { origin, words: words(&rest[4..4 + count]) };
+        image.check_fits()?;
+        if let Some(other) = sections.iter().find(|o| overlaps(o, &image)) {
+            return Err(invalid(format!(
+                "section at x{:04X} overlaps the section at x{:04X}",
+                image.origin, other.origin
+            )));
+        }
+        sections.push(image);
+        rest = &rest[4 + count..];
+    }
+    Ok(sections)
+}
+
+// every problem that would stop the file loading, or leave nothing to load,
+// where sections_from_bytes stops at the first. empty when the file is valid
+pub fn check_bytes(bytes: &[u8]) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut sections: Vec<Image> = Vec::new();
+    if bytes.is_empty() {
+        problems.push("file is empty".to_string());
+    } else if strip_header(bytes, &OBJECT_MAGIC, OBJECT_VERSION).is_some() {
+        match Object::from_bytes(bytes) {
+            Ok(object) => {
+                problems.extend(
+                    object.references.iter().map(|r| format!("unresolved external label '{}', link the object first", r.symbol)),
+                );
+                sections = object.sections;
+            }
+            Err(e) => problems.push(e.to_string()),
+        }
+    } else if let Some(mut rest) = strip_header(bytes, &SECTIONS_MAGIC, SECTIONS_VERSION) {
+        while !rest.is_empty() {
+            if rest.len() < 4 {
+                problems.push("truncated section header".to_string());
+                break;
+            }
+            let origin = (rest[0] as u16) << 8 | rest[1] as u16;
+            let count = ((rest[2] as usize) << 8 | rest[3] as usize) * 2;
+            if rest.len() < 4 + count {
+                problems.push(format!("section at x{:04X} is truncated", origin));
+                break;
+            }
+            sections.push(Image { origin, words: words(&rest[4..4 + count]) });
+            rest = &rest[4 + count..];
+        }
+        if sections.is_empty() && problems.is_empty() {
+            problems.push("file has no sections".to_string());
+        }
+    } else if bytes.len() < 2 {
+        problems.push("image has no origin".to_string());
+    } else {
+        if bytes.len() % 2 == 1 {
+            problems.push(format!("odd byte count after the origin ({}), the last byte is ignored", bytes.len() - 2));
+        }
+        sections.push(Image { origin: (bytes[0] as u16) << 8 | bytes[1] as u16, words: words(&bytes[2..]) });
+    }
+    for (i, image) in sections.iter().enumerate() {
+        if image.words.is_empty() {
+            problems.push(format!("section at x{:04X} has no words", image.origin));
+        }
+        if let Err(e) = image.check_fits().and_then(|_| image.check_devices()) {
+            problems.push(e.to_string());
+        }
+        if let Some(other) = sections[..i].iter().find(|o| overlaps(o, image)) {
+            problems.push(format!("section at x{:04X} overlaps the section at x{:04X}", image.origin, other.origin));
+        }
+    }
+    problems
+}
+
+pub(crate) fn overlaps(a: &Image, b: &Image) -> bool {
+    (a.origin as u32) < b.end() && (b.origin as u32) < a.end()
+}
+
+// read every section of an object file
+pub fn read_sections(path: &str) -> io::Result<Vec<Image>> {
+    sections_from_bytes(&fs::read(path)?)
+}
+
+// decode standard base64, e.g. an image passed as text. whitespace is
+// skipped and the trailing '=' padding is optional
+pub fn decode_base64(text: &str) -> io::Result<Vec<u8>> {
+    let digits: Vec<u8> = text.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    let unpadded = digits.strip_suffix(b"==").or_else(|| digits.strip_suffix(b"=")).unwrap_or(&digits);
+    if unpadded.len() % 4 == 1 || (unpadded.len() != digits.len() && !digits.len().is_multiple_of(4)) {
+        return Err(invalid(format!("invalid base64: {} digits can't encode whole bytes", digits.len())));
+    }
+    let mut bytes = Vec::with_capacity(unpadded.len() * 3 / 4);
+    let mut bits = 0u32;
+    let mut count = 0;
+    for (i, &d) in unpadded.iter().enumerate() {
+        let value = match d {
+            b'A'..=b'Z' => d - b'A',
+            b'a'..=b'z' => d - b'a' + 26,
+            b'0'..=b'9' => d - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return Err(invalid(format!("invalid base64: '{}' at digit {}", d as char, i + 1))),
+        };
+        bits = bits << 6 | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+// a word that differs between two images, None where an image doesn't cover the address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WordDiff {
+    pub address: u16,
+    pub a: Option<u16>,
+    pub b: Option<u16>,
+}
+
+fn loaded_words(sections: &[Image]) -> BTreeMap<u16, u16> {
+    sections
+        .iter()
+        .flat_map(|s| s.words.iter().enumerate().map(|(i, w)| (s.origin + i as u16, *w)))
+        .collect()
+}
+
+// compare the loaded regions of two images address by address
+pub fn diff(a: &[Image], b: &[Image]) -> Vec<WordDiff> {
+    let a = loaded_words(a);
+    let b = loaded_words(b);
+    let mut addresses: Vec<u16> = a.keys().chain(b.keys()).copied().collect();
+    addresses.sort_unstable();
+    addresses.dedup();
+    addresses
+        .into_iter()
+        .map(|address| WordDiff { address, a: a.get(&address).copied(), b: b.get(&address).copied() })
+        .filter(|d| d.a != d.b)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_past_the_end_of_memory_is_refused() {
+        let image = Image { origin: 0xFFF0, words: vec![1; 0x20] };
+        let e = Image::from_bytes(&image.to_bytes()).unwrap_err();
+        assert_eq!(e.to_string(), "image at xFFF0 has 32 words and runs 16 words past the end of memory");
+        // ending exactly at xFFFF fits
+        assert!(Image { origin: 0xFFF0, words: vec![1; 0x10] }.check_fits().is_ok());
+    }
+
+    #[test]
+    fn image_in_the_device_registers_is_refused() {
+        let image = Image { origin: 0xFE00, words: vec![0x1234] };
+        let e = image.check_devices().unwrap_err();
+        assert_eq!(e.to_string(), "image at xFE00-xFE00 would load into the device registers at xFE00-xFFFF");
+        // one reaching into them from below too, one ending at xFDFF loads
+        assert!(Image { origin: 0xFDFF, words: vec![0; 2] }.check_devices().is_err());
+        assert!(Image { origin: 0xFDFF, words: vec![0; 1] }.check_devices().is_ok());
+        assert_eq!(check_bytes(&image.to_bytes()), [e.to_string()]);
+    }
+
+    #[test]
+    fn plain_obj_spelling_a_magic_stays_plain() {
+        // origin x4C43 and first word x3353 are the bytes "LC3S", the next
+        // word isn't the version
+        for second in [0x1234, 0x0000, 0x0002] {
+            let image = Image { origin: 0x4C43, words: vec![0x3353, second, 0x00FF] };
+            assert_eq!(sections_from_bytes(&image.to_bytes()).unwrap(), std::slice::from_ref(&image));
+            assert!(check_bytes(&image.to_bytes()).is_empty());
+        }
+        // the same for "LC3L" of a linkable object
+        let image = Image { origin: 0x4C43, words: vec![0x334C, 0x1234] };
+        assert_eq!(sections_from_bytes(&image.to_bytes()).unwrap(), [image]);
+    }
+
+    #[test]
+    fn sections_round_trip() {
+        let sections = vec![Image { origin: 0x3000, words: vec![1, 2] }, Image { origin: 0x4000, words: vec![3] }];
+        let bytes = sections_to_bytes(&sections);
+        assert!(bytes.starts_with(b"LC3S\x00\x01"));
+        assert_eq!(sections_from_bytes(&bytes).unwrap(), sections);
+        assert!(check_bytes(&bytes).is_empty());
+        // one section is a plain .obj
+        assert_eq!(sections_to_bytes(&sections[..1]), [0x30, 0x00, 0, 1, 0, 2]);
+    }
+}
@@ -0,0 +1,92 @@
+pub mod assembler;
+pub mod branches;
+pub mod cfg;
+pub mod color;
+pub mod consts;
+pub mod coverage;
+pub mod debugger;
+pub mod disasm;
+pub mod embed;
+pub mod filetraps;
+pub mod image;
+pub mod link;
+pub mod loops;
+pub mod memmap;
+pub mod repl;
+pub mod runner;
+pub mod screen;
+pub mod symbols;
+pub mod testing;
+pub mod trace;
+pub mod traptimes;
+#[cfg(all(feature = "tui", not(target_arch = "wasm32")))]
+pub mod tui;
+pub mod vm;
+
+use std::fmt;
+use std::io;
+
+pub use crate::assembler::{assemble, AsmError, Program};
+pub use crate::runner::{assemble_and_run, run_batch, run_cooperative, run_headless, run_headless_utf8, RunResult};
+pub use crate::vm::VM;
+
+// errors surfaced by the embedding API
+#[derive(Debug)]
+pub enum Error {
+    // the source failed to assemble
+    Assemble(AsmError),
+    // the image could not be loaded
+    Load(io::Error),
+    // the program asked for more input than was given
+    InputExhausted,
+    // the program didn't halt within the step limit
+    StepLimit(u64),
+    // the program wrote to a protected address
+    WriteProtected(u16),
+    // the program executed the reserved opcode with strict opcodes on
+    IllegalOpcode { address: u16, word: u16 },
+    // the program wrote more than the output limit
+    OutputLimit(u64),
+    // the store at pc wrote into the null guard's range
+    NullWrite { address: u16, pc: u16 },
+    // the machine state at pc recurred, the program would loop forever
+    InfiniteLoop { pc: u16, period: u64 },
+    // the program's output isn't valid UTF-8
+    Utf8(std::string::FromUtf8Error),
+    // a cooperative run was given a quantum of 0 instructions
+    ZeroQuantum,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Assemble(e) => write!(f, "assemble failed: {}", e),
+            Error::Load(e) => write!(f, "load image failed: {}", e),
+            Error::InputExhausted => write!(f, "program read past the end of its input"),
+            Error::StepLimit(n) => write!(f, "program did not halt within {} instructions", n),
+            Error::WriteProtected(a) => write!(f, "program wrote to protected address x{:04X}", a),
+            Error::IllegalOpcode { address, word } => write!(f, "illegal opcode x{:04X} at x{:04X}", word, address),
+            Error::OutputLimit(n) => write!(f, "program wrote more than {} bytes", n),
+            Error::InfiniteLoop { pc, period } => {
+                write!(f, "program loops forever at x{:04X}, its state recurs every {} instructions", pc, period)
+            }
+            Error::NullWrite { address, pc } => write!(f, "store at x{:04X} wrote x{:04X}, a likely null pointer", pc, address),
+            Error::Utf8(e) => write!(f, "output is not UTF-8: {}", e),
+            Error::ZeroQuantum => write!(f, "a quantum of 0 instructions never makes progress"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<AsmError> for Error {
+    fn from(e: AsmError) -> Self {
+        Error::Assemble(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Load(e)
+    }
+}
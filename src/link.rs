@@ -0,0 +1,267 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+
+use crate::image::{header, overlaps, strip_header, Image};
+
+// leads a linkable object file, with the format version after it as a
+// word: the section count and each section as its origin, word count and
+// words, the export count and each export as its address and name, then
+// the reference count and each reference as its address, kind and name.
+// names are a length byte and the name's bytes
+pub const OBJECT_MAGIC: [u8; 4] = *b"LC3L";
+pub const OBJECT_VERSION: u16 = 1;
+
+// the field a reference to an external label fills in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefKind {
+    // a whole word, from .FILL
+    Word,
+    // the PC-relative offset of BR, LD, LDI, LEA, ST and STI
+    Offset9,
+    // the PC-relative offset of JSR
+    Offset11,
+}
+
+impl RefKind {
+    fn code(self) -> u8 {
+        match self {
+            RefKind::Word => 0,
+            RefKind::Offset9 => 1,
+            RefKind::Offset11 => 2,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<RefKind> {
+        match code {
+            0 => Some(RefKind::Word),
+            1 => Some(RefKind::Offset9),
+            2 => Some(RefKind::Offset11),
+            _ => None,
+        }
+    }
+}
+
+// a use of a label defined in another object, at the address of its word
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub address: u16,
+    pub kind: RefKind,
+    pub symbol: String,
+}
+
+// an assembled file as the linker sees it: its sections, the labels it
+// exports and the external labels it uses. addresses are absolute, each
+// file keeps its own .ORIG
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Object {
+    pub sections: Vec<Image>,
+    pub exports: BTreeMap<String, u16>,
+    pub references: Vec<Reference>,
+}
+
+// why objects failed to link
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkError {
+    // a label exported by more than one object
+    Duplicate(String),
+    // a label used but exported by no object
+    Unresolved(String),
+    // sections of two objects load over each other
+    Overlap { a: u16, b: u16 },
+    // a label too far from the instruction using it
+    OutOfRange { address: u16, symbol: String },
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::Duplicate(s) => write!(f, "label '{}' is exported more than once", s),
+            LinkError::Unresolved(s) => write!(f, "unresolved external label '{}'", s),
+            LinkError::Overlap { a, b } => write!(f, "section at x{:04X} overlaps the section at x{:04X}", a, b),
+            LinkError::OutOfRange { address, symbol } => {
+                write!(f, "label '{}' is out of range of the instruction at x{:04X}", symbol, address)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// reads big-endian fields off the front of an object file
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() < n {
+            return Err(invalid("truncated object file"));
+        }
+        let (head, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(head)
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn word(&mut self) -> io::Result<u16> {
+        let b = self.take(2)?;
+        Ok((b[0] as u16) << 8 | b[1] as u16)
+    }
+
+    fn name(&mut self) -> io::Result<String> {
+        let len = self.byte()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| invalid("label name is not UTF-8"))
+    }
+}
+
+fn push_name(bytes: &mut Vec<u8>, name: &str) {
+    // the assembler's labels are short, longer names are cut at 255 bytes
+    let name = &name.as_bytes()[..name.len().min(255)];
+    bytes.push(name.len() as u8);
+    bytes.extend_from_slice(name);
+}
+
+impl Object {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = header(&OBJECT_MAGIC, OBJECT_VERSION);
+        bytes.extend_from_slice(&(self.sections.len() as u16).to_be_bytes());
+        for image in &self.sections {
+            bytes.extend_from_slice(&image.origin.to_be_bytes());
+            bytes.extend_from_slice(&(image.words.len() as u16).to_be_bytes());
+            for w in &image.words {
+                bytes.extend_from_slice(&w.to_be_bytes());
+            }
+        }
+        bytes.extend_from_slice(&(self.exports.len() as u16).to_be_bytes());
+        for (name, address) in &self.exports {
+            bytes.extend_from_slice(&address.to_be_bytes());
+            push_name(&mut bytes, name);
+        }
+        bytes.extend_from_slice(&(self.references.len() as u16).to_be_bytes());
+        for r in &self.references {
+            bytes.extend_from_slice(&r.address.to_be_bytes());
+            bytes.push(r.kind.code());
+            push_name(&mut bytes, &r.symbol);
+        }
+        bytes
+    }
+
+    // parse a linkable object file, any other object file is read as one
+    // with nothing to export or resolve
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Object> {
+        let Some(rest) = strip_header(bytes, &OBJECT_MAGIC, OBJECT_VERSION) else {
+            let sections = crate::image::sections_from_bytes(bytes)?;
+            return Ok(Object { sections, ..Object::default() });
+        };
+        let mut r = Reader { bytes: rest };
+        let mut object = Object::default();
+        for _ in 0..r.word()? {
+            let origin = r.word()?;
+            let count = r.word()? as usize;
+            let words = r.take(count * 2)?.chunks_exact(2).map(|w| (w[0] as u16) << 8 | w[1] as u16).collect();
+            let image = Image { origin, words };
+            image.check_fits()?;
+            object.sections.push(image);
+        }
+        for _ in 0..r.word()? {
+            let address = r.word()?;
+            object.exports.insert(r.name()?, address);
+        }
+        for _ in 0..r.word()? {
+            let address = r.word()?;
+            let kind = RefKind::from_code(r.byte()?).ok_or_else(|| invalid("unknown reference kind"))?;
+            object.references.push(Reference { address, kind, symbol: r.name()? });
+        }
+        if !r.bytes.is_empty() {
+            return Err(invalid("trailing bytes after the references"));
+        }
+        Ok(object)
+    }
+}
+
+// patch the field a reference names with the label's address
+fn patch(word: &mut u16, r: &Reference, target: u16) -> Result<(), LinkError> {
+    let bits = match r.kind {
+        RefKind::Word => {
+            *word = target;
+            return Ok(());
+        }
+        RefKind::Offset9 => 9,
+        RefKind::Offset11 => 11,
+    };
+    let offset = target as i32 - (r.address as i32 + 1);
+    let limit = 1 << (bits - 1);
+    if !(-limit..limit).contains(&offset) {
+        return Err(LinkError::OutOfRange { address: r.address, symbol: r.symbol.clone() });
+    }
+    let mask = (1u16 << bits) - 1;
+    *word = (*word & !mask) | (offset as u16 & mask);
+    Ok(())
+}
+
+// resolve every object's external labels against the others' exports and
+// merge their sections into one image
+pub fn link(objects: &[Object]) -> Result<Vec<Image>, LinkError> {
+    let mut exports = BTreeMap::new();
+    for object in objects {
+        for (name, &address) in &object.exports {
+            if exports.insert(name.clone(), address).is_some() {
+                return Err(LinkError::Duplicate(name.clone()));
+            }
+        }
+    }
+    let mut sections: Vec<Image> = Vec::new();
+    for object in objects {
+        for image in &object.sections {
+            if let Some(other) = sections.iter().find(|o| overlaps(o, image)) {
+                return Err(LinkError::Overlap { a: image.origin, b: other.origin });
+            }
+            sections.push(image.clone());
+        }
+    }
+    for r in objects.iter().flat_map(|o| &o.references) {
+        let target = *exports.get(&r.symbol).ok_or_else(|| LinkError::Unresolved(r.symbol.clone()))?;
+        // the assembler only records references inside its own sections
+        let Some(image) = sections.iter_mut().find(|s| (s.origin as u32..s.end()).contains(&(r.address as u32))) else {
+            continue;
+        };
+        let i = (r.address - image.origin) as usize;
+        patch(&mut image.words[i], r, target)?;
+    }
+    Ok(sections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image::sections_from_bytes;
+
+    #[test]
+    fn linkable_object_round_trips() {
+        let object = Object {
+            sections: vec![Image { origin: 0x3000, words: vec![0xE000, 0xF025] }],
+            exports: [("MAIN".to_string(), 0x3000)].into_iter().collect(),
+            references: Vec::new(),
+        };
+        let bytes = object.to_bytes();
+        assert!(bytes.starts_with(b"LC3L\x00\x01"));
+        assert_eq!(Object::from_bytes(&bytes).unwrap(), object);
+        assert_eq!(sections_from_bytes(&bytes).unwrap(), object.sections);
+    }
+
+    #[test]
+    fn plain_obj_spelling_the_magic_is_not_an_object() {
+        // an image at x4C43 starting with x334C, "LC3L" without the version after it
+        let image = Image { origin: 0x4C43, words: vec![0x334C, 0x1234] };
+        let object = Object::from_bytes(&image.to_bytes()).unwrap();
+        assert_eq!(object, Object { sections: vec![image], ..Object::default() });
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::{HashMap, VecDeque};
+
+// detects a program that can only loop forever: the machine is
+// deterministic, so once its whole state after an instruction recurs it
+// repeats the same steps again and again. the state is the registers, the
+// processor status and all of memory, kept as a hash that each memory
+// write updates, so a step costs the same whatever the memory size.
+//
+// the limits: only the last `capacity` states are remembered, a loop longer
+// than that isn't caught. reading input forgets every state, as the next key
+// can change what the program does. two different states hashing alike
+// would be a false report, unlikely with 64 bit hashes
+
+// states remembered by default, a few megabytes
+pub const DEFAULT_CAPACITY: usize = 100_000;
+
+// a 64 bit mix, splitmix64's finalizer
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+// what a word adds to the memory hash, by xor so a write swaps it out
+fn word_hash(address: u16, val: u16) -> u64 {
+    mix((address as u64) << 16 | val as u64)
+}
+
+pub struct LoopDetector {
+    memory: u64,
+    capacity: usize,
+    // the states seen with the instruction count at each, oldest first
+    seen: HashMap<u64, u64>,
+    order: VecDeque<u64>,
+}
+
+impl LoopDetector {
+    // remember up to capacity states, starting from this memory
+    pub fn new(capacity: usize, memory: &[u16]) -> LoopDetector {
+        let mut detector = LoopDetector { memory: 0, capacity, seen: HashMap::new(), order: VecDeque::new() };
+        detector.rehash(memory);
+        detector
+    }
+
+    // recompute the memory hash after memory changed wholesale
+    pub fn rehash(&mut self, memory: &[u16]) {
+        self.memory = memory.iter().enumerate().fold(0, |h, (a, &w)| h ^ word_hash(a as u16, w));
+        self.forget();
+    }
+
+    pub fn write(&mut self, address: u16, old: u16, new: u16) {
+        self.memory ^= word_hash(address, old) ^ word_hash(address, new);
+    }
+
+    // drop every state seen, e.g. when input arrives
+    pub fn forget(&mut self) {
+        self.seen.clear();
+        self.order.clear();
+    }
+
+    // record the state after instruction number count, returning how many
+    // instructions ago the same state was seen if it recurred
+    pub fn check(&mut self, regs: &[u16], status: u16, count: u64) -> Option<u64> {
+        let state = regs.iter().chain([&status]).fold(self.memory, |h, &r| mix(h ^ r as u64));
+        if let Some(&then) = self.seen.get(&state) {
+            return Some(count - then);
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(state, count);
+        self.order.push_back(state);
+        None
+    }
+}
@@ -1,463 +1,1000 @@
-use std::{env, io, process};
+use std::{env, fs, io, process, thread};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::fs::File;
-use std::io::{Read, stdout, Write};
-use std::ptr::read;
-use crate::Op::TRAP;
-use crate::RegisterType::{COND, PC, R0, R7};
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use lc3_vm_rust::{assemble, color, filetraps, run_headless, Error};
+use lc3_vm_rust::assembler::{parse_number, unescape};
+use lc3_vm_rust::cfg::FlowGraph;
+use lc3_vm_rust::debugger::{register_index, Debugger};
+use lc3_vm_rust::disasm::{disassemble, dump_vectors, opcode_table};
+use lc3_vm_rust::image::{check_bytes, decode_base64, diff, read_sections, sections_from_bytes, sections_to_bytes, Image};
+use lc3_vm_rust::link::{link, Object};
+use lc3_vm_rust::loops::DEFAULT_CAPACITY;
+use lc3_vm_rust::memmap::memory_map;
+use lc3_vm_rust::repl::Repl;
+use lc3_vm_rust::symbols::{read_symbols, Symbols};
+use lc3_vm_rust::trace::{decode_binary, effective_access, jump_line, BinaryTrace, Replay};
+use lc3_vm_rust::vm::RegisterType::PC;
+use lc3_vm_rust::vm::{read_image, Control, CycleCosts, HaltReason, MR_KBSR, OPCODE_NAMES, PC_START, POISON, VM};
 use termios::*;
 
-const MEMORY_MAX: usize = 1 << 16;
-const REG_COUNT: usize = 10;
-const PC_START: u16 = 0x3000;
-const MR_KBSR: u16 = 0xFE00;
-const MR_KBDR: u16 = 0xFE02;  /* keyboard data */
-
-enum RegisterType {
-    R0,
-    R1,
-    R2,
-    R3,
-    R4,
-    R5,
-    R6,
-    R7,
-    PC,
-    COND,
-}
-
-#[derive(Debug)]
-enum Op {
-    BR,
-    ADD,
-    LD,
-    ST,
-    JSR,
-    AND,
-    LDR,
-    STR,
-    RTI,
-    NOT,
-    LDI,
-    STI,
-    JMP,
-    RES,
-    LEA,
-    TRAP,
-    Unknown,
-}
-
-// get op enum by op code
-fn get_op(op_code: u16) -> Op {
-    match op_code {
-        0 => Op::BR,
-        1 => Op::ADD,
-        2 => Op::LD,
-        3 => Op::ST,
-        4 => Op::JSR,
-        5 => Op::AND,
-        6 => Op::LDR,
-        7 => Op::STR,
-        8 => Op::RTI,
-        9 => Op::NOT,
-        10 => Op::LDI,
-        11 => Op::STI,
-        12 => Op::JMP,
-        13 => Op::RES,
-        14 => Op::LEA,
-        15 => Op::TRAP,
-        _ => Op::Unknown,
-    }
-}
-
-struct VM {
-    regs: Vec<u16>,
-    memory: Vec<u16>,
-}
-
-impl VM {
-    fn new() -> VM {
-        let mut vm = VM { regs: vec![0u16; REG_COUNT], memory: vec![0u16; MEMORY_MAX] };
-        vm.write_reg(PC, PC_START);
-        vm
-    }
-
-    // get the register index by register type enum
-    fn get_index(t: RegisterType) -> usize {
-        match t {
-            RegisterType::R0 => 0,
-            RegisterType::R1 => 1,
-            RegisterType::R2 => 2,
-            RegisterType::R3 => 3,
-            RegisterType::R4 => 4,
-            RegisterType::R5 => 5,
-            RegisterType::R6 => 6,
-            RegisterType::R7 => 7,
-            RegisterType::PC => 8,
-            RegisterType::COND => 9,
-        }
-    }
-
-    // read reg by register type enum
-    fn read_reg(&self, t: RegisterType) -> u16 {
-        self.regs[Self::get_index(t)]
-    }
-
-    // write reg by register type enum
-    fn write_reg(&mut self, t: RegisterType, v: u16) {
-        self.regs[Self::get_index(t)] = v;
-    }
-
-    // read reg by register index
-    fn read_reg_by_index(&self, i: u16) -> u16 {
-        self.regs[i as usize]
-    }
-
-    // write reg by register index
-    fn write_reg_by_index(&mut self, i: u16, v: u16) {
-        self.regs[i as usize] = v;
-    }
-
-    fn read_pc(&mut self) -> u16 {
-        self.regs[Self::get_index(PC)]
-    }
-
-    // program counter increment 1
-    fn add_pc(&mut self) {
-        self.regs[Self::get_index(PC)] += 1;
-    }
-
-    fn read_memory(&mut self, address: u16) -> u16 {
-        if (address == MR_KBSR) {
-            if (check_key()) {
-                self.write_memory(MR_KBSR, 1 << 15);
-                self.write_memory(MR_KBDR, read_char() as u16);
-            } else {
-                self.write_memory(MR_KBSR, 0);
+const USAGE: &str = "\
+lc3 [debug [--break-after N] [--tui]] [--os os-image] [--dump-vectors] [--coverage] [--branch-stats] [--trap-times] [--trace] [--trace-addresses] [--no-color] [--trace-bin trace.bin] [--replay trace.bin] [--follow-jumps] [--trace-traps] [--screen] [--overflow] [--poison] [--cfg out.dot] [--input-str TEXT] [--debug-trap] [--protect START:END]... [--null-guard] [--null-guard-range START:END] [--null-guard-warn] [--echo] [--map-key FROM=TO]... [--strip-high-bit] [--syms file.sym] [--entry LABEL] [--pc ADDR] [--stats] [--enable-file-traps] [--strict-opcodes] [--strict-mmio] [--interrupts] [--detect-loops] [--max-output BYTES] [--run-until-mem ADDR=VAL] [--poke ADDR=VAL]... [--rom file.bin@ADDR]... [--stack START:END] [--output out.txt] [--quiet] [--core-dump core.obj (x0000-xFDFF)] [--cycles] [--cycle-cost OP|MEM=N]... [--max-cycles N] [--enable-budget-trap] [--timeout-secs N] [--summary-json [--report R0,mem:ADDR,...]] [image-file | - | --image-b64 BASE64] [-- args...]
+lc3 disasm [--no-color] [--range START:END] image.obj
+lc3 diff [--no-color] a.obj b.obj
+lc3 verify image.obj [--input in.txt] --expect out.txt
+lc3 map [--os os-image] [--syms file.sym] image.obj
+lc3 info image.obj
+lc3 check image.obj
+lc3 asm file.asm [-o out.obj]
+lc3 link a.obj b.obj... -o out.obj
+lc3 repl
+lc3 opcodes
+lc3 trace-decode trace.bin";
+
+// put the terminal on fd into raw mode so the VM's keyboard is interactive
+fn setup_terminal(stdin: RawFd) {
+    // terminal setup reference https://github.com/digorithm/LC-3-Rust
+    // Some tricks to make the VM's terminal be interactive
+    let termios = Termios::from_fd(stdin).unwrap();
+
+    // make a mutable copy of termios
+    // that we will modify
+    let mut new_termios = termios;
+    new_termios.c_iflag &= IGNBRK | BRKINT | PARMRK | ISTRIP | INLCR | IGNCR | ICRNL | IXON;
+    new_termios.c_lflag &= !(ICANON | ECHO); // no echo and canonical mode
+
+    tcsetattr(stdin, TCSANOW, &new_termios).unwrap();
+}
+
+// split off --no-color and decide whether to color the output
+fn color_flag(args: &[String]) -> (Vec<String>, bool) {
+    let no_color = args.iter().any(|a| a == "--no-color");
+    let rest = args.iter().filter(|a| *a != "--no-color").cloned().collect();
+    (rest, color::enabled(no_color))
+}
+
+// disassembly padded to width, padding is applied before coloring
+fn disassembly(word: u16, address: u16, width: usize, color: bool) -> String {
+    let text = disassemble(word, address);
+    let pad = " ".repeat(width.saturating_sub(text.len()));
+    if color {
+        format!("{}{}", color::colorize(&text), pad)
+    } else {
+        format!("{}{}", text, pad)
+    }
+}
+
+// the instruction about to execute at pc like trace_line, the disassembly
+// padded to width so an access after it lines up
+fn trace_text(vm: &VM, pc: u16, width: usize, color: bool) -> String {
+    let instr = vm.fetch(pc);
+    format!("x{:04X}  x{:04X}  {}", pc, instr, disassembly(instr, pc, width, color))
+}
+
+// list every loaded word of the image with its disassembly
+fn disasm_image(args: &[String]) {
+    let (args, color) = color_flag(args);
+    let mut args = args.iter();
+    let mut path = None;
+    let mut range = (0, 0xffff);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--range" => range = parse_range(args.next().expect(USAGE)),
+            _ if path.is_none() => path = Some(arg),
+            _ => panic!("{}", USAGE),
+        }
+    }
+    let path = path.expect(USAGE);
+    let (start, end) = range;
+    for section in read_sections(path).expect("Read image file failed") {
+        for (i, w) in section.words.iter().enumerate() {
+            let address = section.origin.wrapping_add(i as u16);
+            if address < start || address > end {
+                continue;
             }
+            println!("x{:04X}  x{:04X}  {}", address, w, disassembly(*w, address, 0, color));
         }
-        self.memory[address as usize]
     }
+}
 
-    // write val to memory
-    fn write_memory(&mut self, address: u16, val: u16) {
-        self.memory[address as usize] = val;
+// one side of a diff line: the word and its disassembly, or blank outside the image
+fn diff_column(word: Option<u16>, address: u16, color: bool) -> String {
+    match word {
+        Some(w) => format!("x{:04X} {}", w, disassembly(w, address, 20, color)),
+        None => format!("{:<26}", "--"),
     }
+}
 
-    // update cond register by given val
-    fn update_flags_by_val(&mut self, val: u16) {
-        if val == 0 {
-            self.write_reg(COND, 1 << 1); // zero
-        } else if ((val >> 15) & 0x1) == 1 {
-            self.write_reg(COND, 1 << 2); // positive
-        } else {
-            self.write_reg(COND, 1 << 0); // negative
+fn layout(image: &Image) -> (u16, usize) {
+    (image.origin, image.words.len())
+}
+
+// print every address where the two images differ, exit nonzero if any does
+fn diff_images(args: &[String]) {
+    let (args, color) = color_flag(args);
+    let [a_path, b_path] = args.as_slice() else {
+        panic!("{}", USAGE);
+    };
+    let a = read_sections(a_path).expect("Read image file failed");
+    let b = read_sections(b_path).expect("Read image file failed");
+    if a.iter().map(layout).ne(b.iter().map(layout)) {
+        for (path, sections) in [(a_path, &a), (b_path, &b)] {
+            for s in sections.iter() {
+                println!("{}: origin x{:04X}, {} words", path, s.origin, s.words.len());
+            }
         }
     }
-
-    // update cond register by Register Index
-    fn update_flags_by_index(&mut self, i: u16) {
-        let val = self.read_reg_by_index(i);
-        self.update_flags_by_val(val);
+    let diffs = diff(&a, &b);
+    for d in &diffs {
+        let line = format!("x{:04X}  {} | {}", d.address, diff_column(d.a, d.address, color), diff_column(d.b, d.address, color));
+        println!("{}", line.trim_end());
     }
+    process::exit(if diffs.is_empty() { 0 } else { 1 });
+}
 
-    // update cond register by RegisterType
-    fn update_flags(&mut self, t: RegisterType) {
-        let val = self.read_reg(t);
-        self.update_flags_by_val(val);
+// a program argument, a number like #5, x10 or -3
+fn parse_arg(arg: &str) -> u16 {
+    match parse_number(arg) {
+        Some(v) if (-0x8000..=0xffff).contains(&v) => v as u16,
+        _ => panic!("invalid program argument '{}', expected a 16 bit number", arg),
     }
 }
 
-// read a char from stdin
-fn read_char() -> u8 {
-    let mut buf = [0u8];
-    io::stdin().read_exact(&mut buf).unwrap();
-    buf[0]
+// a memory cell and a value written ADDR=VAL, e.g. x4000=42
+fn parse_cell(cell: &str) -> (u16, u16) {
+    let address = |a: &str| parse_number(a).filter(|v| (0..=0xffff).contains(v)).map(|v| v as u16);
+    let value = |v: &str| parse_number(v).filter(|v| (-0x8000..=0xffff).contains(v)).map(|v| v as u16);
+    match cell.split_once('=').map(|(a, v)| (address(a), value(v))) {
+        Some((Some(address), Some(value))) => (address, value),
+        _ => panic!("invalid memory cell '{}', expected ADDR=VAL with a 16 bit address and value", cell),
+    }
 }
 
-fn check_key() -> bool {
-    true
+// set one cost written NAME=N, NAME is an opcode mnemonic or MEM for a memory access
+fn parse_cost(costs: &mut CycleCosts, cost: &str) {
+    let (name, n) = match cost.split_once('=').map(|(name, n)| (name.to_ascii_uppercase(), n.parse::<u64>())) {
+        Some((name, Ok(n))) => (name, n),
+        _ => panic!("invalid cycle cost '{}', expected OP=N or MEM=N", cost),
+    };
+    if name == "MEM" {
+        costs.memory_access = n;
+    } else if let Some(op) = OPCODE_NAMES.iter().position(|o| *o == name) {
+        costs.opcodes[op] = n;
+    } else {
+        panic!("unknown opcode '{}' in cycle cost '{}'", name, cost);
+    }
 }
 
-// read the image instruction to the memory
-fn read_image(image_path: &str, vm: &mut VM) -> io::Result<()> {
-    let mut file = File::open(image_path).expect(&format!("Open image file {} failed!", image_path));
-    let mut buf = [0u8; 2];
-    // read the first 2 byte, it means that the program start position in memory
-    file.read_exact(&mut buf)?;
-    let mut origin = (buf[0] as u16) << 8 | buf[1] as u16;
-    // println!("start address: {}", origin);
+// a memory address like x3000, 0x3000 or 12288
+fn parse_address(address: &str) -> u16 {
+    match parse_number(address) {
+        Some(v) if (0..=0xffff).contains(&v) => v as u16,
+        _ => panic!("invalid address '{}'", address),
+    }
+}
 
-    loop {
-        // read 2 byte
-        match file.read_exact(&mut buf) {
-            Ok(_) => {
-                vm.write_memory(origin, (buf[0] as u16) << 8 | buf[1] as u16);
-                origin += 1;
-            }
-            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                // finish read file
-                break;
-            }
-            Err(e) => {
-                return Err(e);
-            }
-        }
+// a key for --map-key, a character with the .STRINGZ escapes or a byte value
+fn parse_key(key: &str) -> u8 {
+    match unescape(key).as_deref().map(str::as_bytes) {
+        Ok(&[c]) => c,
+        _ => match parse_number(key) {
+            Some(v) if (0..=0xff).contains(&v) => v as u8,
+            _ => panic!("invalid key '{}', expected a character such as \\n or a byte such as x0D", key),
+        },
     }
-    // println!("end address: {}", origin);
-    Ok(())
 }
 
-// if imm is positive or zero, return the imm
-// otherwise Pad with 1 from high to low
-fn sign_extend(imm: u16, len: i32) -> u16 {
-    if ((imm >> len - 1) & 0x1) == 0 {
-        imm
-    } else {
-        imm | (0xffff << len)
+// FROM=TO
+fn parse_key_map(map: &str) -> (u8, u8) {
+    let Some((from, to)) = map.split_once('=') else {
+        panic!("--map-key {}: expected FROM=TO", map);
+    };
+    (parse_key(from), parse_key(to))
+}
+
+// FILE@ADDR
+fn parse_rom(rom: &str) -> (&str, u16) {
+    let Some((path, address)) = rom.rsplit_once('@') else {
+        panic!("--rom {}: expected FILE@ADDR", rom);
+    };
+    (path, parse_address(address))
+}
+
+// an inclusive address range written START:END, e.g. x3000:x30FF
+fn parse_range(range: &str) -> (u16, u16) {
+    let address = |a: &str| parse_number(a).filter(|v| (0..=0xffff).contains(v)).map(|v| v as u16);
+    match range.split_once(':').map(|(s, e)| (address(s), address(e))) {
+        Some((Some(start), Some(end))) if start <= end => (start, end),
+        _ => panic!("invalid address range '{}', expected START:END", range),
     }
 }
 
-fn br(vm: &mut VM, instr: u16) {
-    let flags = (instr) >> 9 & 0x7;
-    let pc_offset = sign_extend(instr & 0x1ff, 9) as u32;
-    if (flags & vm.read_reg(COND)) != 0 {
-        let pc_val = vm.read_pc() as u32;
-        vm.write_reg(PC, (pc_val + pc_offset) as u16);
+// a value --report adds to the summary
+enum Cell {
+    Register(u16),
+    Memory(u16),
+}
+
+// NAME,NAME,... where a name is a register such as R0 or PC, or mem:ADDR
+fn parse_report(list: &str) -> Vec<(String, Cell)> {
+    let cell = |name: &str| match name.strip_prefix("mem:") {
+        Some(address) => Cell::Memory(parse_address(address)),
+        None => Cell::Register(register_index(name).unwrap_or_else(|| panic!("--report: unknown register '{}'", name))),
+    };
+    list.split(',').map(|name| (name.to_string(), cell(name))).collect()
+}
+
+// a JSON string literal
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
     }
+    out + "\""
 }
 
-fn add(vm: &mut VM, instr: u16) {
-    let r0 = (instr >> 9) & 0x7;
-    let r1 = (instr >> 6) & 0x7;
-    let imm5_flag = (instr >> 5) & 0x1;
-    if imm5_flag == 0 {
-        let r2 = instr & 0x7;
-        // prevent overflow
-        let val1 = vm.read_reg_by_index(r1) as u32;
-        let val2 = vm.read_reg_by_index(r2) as u32;
-        vm.write_reg_by_index(r0, (val1 + val2) as u16);
-    } else {
-        let imm5 = sign_extend(instr & 0x1f, 5) as u32;
-        vm.write_reg_by_index(r0, (vm.read_reg_by_index(r1) as u32 + imm5) as u16);
+// the run as one JSON line for grading scripts. halt_reason is null when the
+// run stopped at --run-until-mem or a --replay divergence, halt_detail has the reason's values, e.g.
+// the address of an illegal opcode. cycles is only there when they're counted
+fn summary_json(vm: &VM, report: &[(String, Cell)]) -> String {
+    let (reason, detail) = match vm.halt_reason() {
+        Some(r) => (json_string(r.name()), json_string(&r.to_string())),
+        None => ("null".to_string(), "null".to_string()),
+    };
+    let mut fields = vec![
+        format!("\"halt_reason\":{}", reason),
+        format!("\"halt_detail\":{}", detail),
+        format!("\"instructions\":{}", vm.instructions()),
+        format!("\"exit_output_len\":{}", vm.output_len()),
+    ];
+    if let Some(cycles) = vm.cycles() {
+        fields.push(format!("\"cycles\":{}", cycles));
+    }
+    if !report.is_empty() {
+        let values: Vec<String> = report
+            .iter()
+            .map(|(name, cell)| {
+                let value = match *cell {
+                    Cell::Register(i) => vm.read_reg_by_index(i),
+                    Cell::Memory(address) => vm.peek(address),
+                };
+                format!("{}:{}", json_string(name), value)
+            })
+            .collect();
+        fields.push(format!("\"report\":{{{}}}", values.join(",")));
     }
-    vm.update_flags_by_index(r0);
+    format!("{{{}}}", fields.join(","))
 }
 
-fn ld(vm: &mut VM, instr: u16) {
-    let r0 = (instr >> 9) & 0x7;
-    let pc_offset = sign_extend(instr & 0x1ff, 9) as u32;
-    let mem_add = (vm.read_pc() as u32 + pc_offset) as u16;
-    let mem_val = vm.read_memory(mem_add);
-    vm.write_reg_by_index(r0, mem_val);
-    vm.update_flags_by_index(r0);
+#[cfg(feature = "tui")]
+fn debug_full_screen(debugger: &mut Debugger) {
+    lc3_vm_rust::tui::run(debugger).expect("debugger failed");
 }
 
-fn st(vm: &mut VM, instr: u16) {
-    let r0 = (instr >> 9) & 0x7;
-    let pc_offset = sign_extend(instr & 0x1ff, 9) as u32;
-    let mem_add = (vm.read_pc() as u32 + pc_offset) as u16;
-    vm.write_memory(mem_add, vm.read_reg_by_index(r0));
+#[cfg(not(feature = "tui"))]
+fn debug_full_screen(_: &mut Debugger) {
+    panic!("--tui needs the tui feature, build with --features tui");
 }
 
-fn jsr(vm: &mut VM, instr: u16) {
-    vm.write_reg(R7, vm.read_reg(PC));
-    let flag = (instr >> 11) & 0x1;
-    if (flag == 0) {
-        let base_r = (instr >> 6) & 0x7;
-        vm.write_reg(PC, vm.read_reg_by_index(base_r));
+// load the image (and the OS) then run it, or debug it
+fn run(args: &[String], debug: bool) {
+    let mut args = args.iter();
+    let mut os_image = None;
+    let mut image = None;
+    let mut image_b64 = None;
+    let mut dump = false;
+    let mut coverage = false;
+    let mut branch_stats = false;
+    let mut trap_times = false;
+    let mut summary = false;
+    let mut report = Vec::new();
+    let mut trace = false;
+    let mut addresses = false;
+    let mut no_color = false;
+    let mut trace_bin = None;
+    let mut replay = None;
+    let mut follow_jumps = false;
+    let mut trace_traps = false;
+    let mut screen = false;
+    let mut overflow = false;
+    let mut timeout = None;
+    let mut poison = false;
+    let mut cfg = None;
+    let mut input_str = None;
+    let mut debug_trap = false;
+    let mut protect = Vec::new();
+    let mut roms = Vec::new();
+    let mut break_after = None;
+    let mut tui = false;
+    let mut null_guard = None;
+    let mut interrupts = false;
+    let mut detect_loops = false;
+    let mut null_guard_abort = true;
+    let mut key_map = Vec::new();
+    let mut echo = false;
+    let mut strip_high_bit = false;
+    let mut syms = None;
+    let mut entry = None;
+    let mut start_pc = None;
+    let mut stats = false;
+    let mut file_traps = false;
+    let mut strict = false;
+    let mut strict_mmio = false;
+    let mut program_args = None;
+    let mut max_output = None;
+    let mut until = None;
+    let mut cycle_costs = None;
+    let mut pokes = Vec::new();
+    let mut stack = None;
+    let mut output_file = None;
+    let mut quiet = false;
+    let mut core_dump = None;
+    let mut max_cycles = None;
+    let mut budget_trap = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--" => {
+                program_args = Some(args.by_ref().map(|a| parse_arg(a)).collect::<Vec<_>>());
+            }
+            "--os" => os_image = Some(args.next().expect(USAGE)),
+            "--image-b64" => image_b64 = Some(args.next().expect(USAGE)),
+            "--dump-vectors" => dump = true,
+            "--coverage" => coverage = true,
+            "--branch-stats" => branch_stats = true,
+            "--trap-times" => trap_times = true,
+            "--summary-json" => summary = true,
+            "--report" => report = parse_report(args.next().expect(USAGE)),
+            "--trace" => trace = true,
+            "--no-color" => no_color = true,
+            "--trace-addresses" => (trace, addresses) = (true, true),
+            "--trace-bin" => trace_bin = Some(args.next().expect(USAGE)),
+            "--replay" => replay = Some(args.next().expect(USAGE)),
+            "--follow-jumps" => follow_jumps = true,
+            "--trace-traps" => trace_traps = true,
+            "--screen" => screen = true,
+            "--overflow" => overflow = true,
+            "--poison" => poison = true,
+            "--cfg" => cfg = Some(args.next().expect(USAGE)),
+            "--debug-trap" => debug_trap = true,
+            "--echo" => echo = true,
+            "--map-key" => key_map.push(parse_key_map(args.next().expect(USAGE))),
+            "--strip-high-bit" => strip_high_bit = true,
+            "--stats" => stats = true,
+            "--enable-file-traps" => file_traps = true,
+            "--strict-opcodes" => strict = true,
+            "--strict-mmio" => strict_mmio = true,
+            "--run-until-mem" => until = Some(parse_cell(args.next().expect(USAGE))),
+            "--max-cycles" => max_cycles = Some(args.next().and_then(|n| n.parse().ok()).expect(USAGE)),
+            "--enable-budget-trap" => budget_trap = true,
+            "--core-dump" => core_dump = Some(args.next().expect(USAGE)),
+            "--output" => output_file = Some(args.next().expect(USAGE)),
+            "--quiet" => quiet = true,
+            "--stack" => stack = Some(parse_range(args.next().expect(USAGE))),
+            "--poke" => pokes.push(parse_cell(args.next().expect(USAGE))),
+            "--cycles" => cycle_costs = Some(cycle_costs.unwrap_or_default()),
+            "--cycle-cost" => parse_cost(cycle_costs.get_or_insert_with(CycleCosts::default), args.next().expect(USAGE)),
+            "--max-output" => max_output = Some(args.next().and_then(|n| n.parse().ok()).expect(USAGE)),
+            "--syms" => syms = Some(args.next().expect(USAGE)),
+            "--entry" => entry = Some(args.next().expect(USAGE)),
+            "--pc" => start_pc = Some(parse_address(args.next().expect(USAGE))),
+            "--protect" => protect.push(parse_range(args.next().expect(USAGE))),
+            "--tui" => tui = true,
+            "--break-after" => break_after = Some(args.next().and_then(|n| n.parse::<u64>().ok()).expect(USAGE)),
+            "--interrupts" => interrupts = true,
+            "--detect-loops" => detect_loops = true,
+            "--null-guard" => null_guard = null_guard.or(Some((0x0000, 0x00FF))),
+            "--null-guard-range" => null_guard = Some(parse_range(args.next().expect(USAGE))),
+            "--null-guard-warn" => (null_guard, null_guard_abort) = (null_guard.or(Some((0x0000, 0x00FF))), false),
+            "--rom" => roms.push(parse_rom(args.next().expect(USAGE))),
+            "--input-str" => {
+                let text = unescape(args.next().expect(USAGE)).unwrap_or_else(|e| panic!("--input-str: {}", e));
+                input_str = Some(text.into_bytes());
+            }
+            "--timeout-secs" => {
+                let secs = args.next().and_then(|n| n.parse().ok()).expect(USAGE);
+                timeout = Some(Duration::from_secs(secs));
+            }
+            _ if image.is_none() => image = Some(arg),
+            _ => panic!("{}", USAGE),
+        }
+    }
+    // the image comes from a file, stdin or the command line itself
+    let image = match (image, &image_b64) {
+        (Some(image), None) => image.as_str(),
+        (None, Some(_)) => "",
+        (None, None) => panic!("{}", USAGE),
+        (Some(_), Some(_)) => panic!("pass an image file or --image-b64, not both"),
+    };
+
+    // --output sends the program's console output to a file, stdout then
+    // only carries the VM's own messages
+    let output: Box<dyn Write + Send> = match output_file {
+        Some(path) => Box::new(BufWriter::new(File::create(path).expect("Create output file failed"))),
+        None => Box::new(io::stdout()),
+    };
+    // --input-str replaces the keyboard. otherwise with the image piped in on
+    // stdin the keyboard is the controlling terminal, without one the program
+    // gets no input at all
+    let (mut vm, keyboard) = if let Some(bytes) = input_str {
+        (VM::with_io(Box::new(Cursor::new(bytes)), output), None)
+    } else if image == "-" {
+        match File::open("/dev/tty") {
+            Ok(tty) => {
+                let fd = tty.as_raw_fd();
+                (VM::with_io(Box::new(tty), output), Some(fd))
+            }
+            Err(_) => (VM::with_io(Box::new(io::empty()), output), None),
+        }
     } else {
-        let pc_offset = sign_extend(instr & 0x7ff, 11) as u32;
-        vm.write_reg(PC, (vm.read_reg(PC) as u32 + pc_offset) as u16);
+        (VM::with_io(Box::new(io::stdin()), output), Some(io::stdin().as_raw_fd()))
+    };
+    vm.set_overflow_tracking(overflow);
+    vm.set_debug_trap(debug_trap);
+    vm.set_echo(echo);
+    for (from, to) in key_map {
+        vm.map_key(from, to);
+    }
+    vm.set_strip_high_bit(strip_high_bit);
+    vm.set_strict_opcodes(strict);
+    vm.set_strict_mmio(strict_mmio);
+    vm.set_trace_traps(trace_traps);
+    if screen {
+        vm.enable_screen();
+    }
+    vm.set_output_limit(max_output);
+    vm.set_cycle_costs(cycle_costs);
+    vm.set_stack(stack);
+    vm.set_cycle_limit(max_cycles);
+    vm.set_budget_trap(budget_trap);
+    if file_traps {
+        filetraps::install(&mut vm);
+    }
+    for (start, end) in protect {
+        vm.protect(start, end);
+    }
+    vm.set_null_guard(null_guard, null_guard_abort);
+    vm.set_interrupts(interrupts);
+    if detect_loops {
+        vm.set_loop_detection(Some(DEFAULT_CAPACITY));
+    }
+    if poison {
+        vm.fill_memory(POISON);
+    }
+
+    // load the OS first so the program can overwrite anything it shares with it,
+    // its trap vector table then replaces the built-in trap routines
+    if let Some(os_image) = os_image {
+        read_image(os_image, &mut vm).expect("Read OS image file failed");
+        vm.set_trap_table(true);
     }
-}
 
-fn and(vm: &mut VM, instr: u16) {
-    let r0 = (instr >> 9) & 0x7;
-    let r1 = (instr >> 6) & 0x7;
-    let imm5_flag = (instr >> 5) & 0x1;
-    if imm5_flag == 0 {
-        let r2 = instr & 0x7;
-        vm.write_reg_by_index(r0, vm.read_reg_by_index(r1) & vm.read_reg_by_index(r2));
+    // load instructions to memory from the give image file
+    let sections = if let Some(text) = image_b64 {
+        decode_base64(text).and_then(|bytes| sections_from_bytes(&bytes))
+    } else if image == "-" {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes).expect("Read image from stdin failed");
+        sections_from_bytes(&bytes)
     } else {
-        let imm5 = sign_extend(instr & 0x1f, 5);
-        vm.write_reg_by_index(r0, vm.read_reg_by_index(r1) & imm5);
-    }
-    vm.update_flags_by_index(r0);
-}
-
-fn ldr(vm: &mut VM, instr: u16) {
-    let r0 = (instr >> 9) & 0x7;
-    let r1 = (instr >> 6) & 0x7;
-    let offset = sign_extend(instr & 0x3f, 6) as u32;
-    let mem_add = (vm.read_reg_by_index(r1) as u32 + offset) as u16;
-    let mem_val = vm.read_memory(mem_add);
-    vm.write_reg_by_index(r0, mem_val);
-    vm.update_flags_by_index(r0);
-}
-
-fn str(vm: &mut VM, instr: u16) {
-    let r0 = (instr >> 9) & 0x7;
-    let r1 = (instr >> 6) & 0x7;
-    let offset = sign_extend(instr & 0x3f, 6) as u32;
-    vm.write_memory((vm.read_reg_by_index(r1) as u32 + offset) as u16, vm.read_reg_by_index(r0));
-}
-
-fn not(vm: &mut VM, instr: u16) {
-    let r0 = (instr >> 9) & 0x7;
-    let r1 = (instr >> 6) & 0x7;
-    vm.write_reg_by_index(r0, !vm.read_reg_by_index(r1));
-    vm.update_flags_by_index(r0);
-}
-
-fn ldi(vm: &mut VM, instr: u16) {
-    let r0 = (instr >> 9) & 0x7;
-    let pc_offset = sign_extend(instr & 0x1ff, 9) as u32;
-    let first_mem_add = (vm.read_pc() as u32 + pc_offset) as u16;
-    let second_mem_add = vm.read_memory(first_mem_add);
-    let res = vm.read_memory(second_mem_add);
-    vm.write_reg_by_index(r0, res);
-    vm.update_flags_by_index(r0);
-}
-
-fn sti(vm: &mut VM, instr: u16) {
-    let r0 = (instr >> 9) & 0x7;
-    let pc_offset = sign_extend(instr & 0x1ff, 9) as u32;
-    let read_mem_add = (vm.read_pc() as u32 + pc_offset) as u16;
-    let write_mem_add = vm.read_memory(read_mem_add);
-    let val = vm.read_reg_by_index(r0);
-    vm.write_memory(write_mem_add, val);
-}
-
-fn jmp(vm: &mut VM, instr: u16) {
-    let r0 = (instr >> 6) & 0x7;
-    vm.write_reg(PC, vm.read_reg_by_index(r0));
-}
-
-fn lea(vm: &mut VM, instr: u16) {
-    let r0 = (instr >> 9) & 0x7;
-    let pc_offset = sign_extend(instr & 0x1ff, 9) as u32;
-    let val = (vm.read_pc() as u32 + pc_offset) as u16;
-    vm.write_reg_by_index(r0, val);
-    vm.update_flags_by_index(r0);
-}
-
-fn trap(vm: &mut VM, instr: u16) {
-    let pc_val = vm.read_pc();
-    vm.write_reg(R7, pc_val);
-    match instr & 0xff {
-        // TRAP_GETC
-        0x20 => {
-            vm.write_reg(R0, read_char() as u16);
-            vm.update_flags(R0);
-        }
-        // TRAP_OUT
-        0x21 => {
-            let c = vm.read_reg(R0) as u8;
-            print!("{}", c as char);
-            stdout().flush().expect("failed to flush");
-        }
-        // TRAP_PUTS
-        0x22 => {
-            let mut start = vm.read_reg(R0);
-            loop {
-                let c = vm.read_memory(start);
-                if c == 0 {
-                    break;
+        read_sections(image)
+    };
+    let sections = sections.expect("Read image file failed");
+    for section in &sections {
+        vm.load_image(section).expect("Read image file failed");
+    }
+    // over the images, so the table is what the program reads
+    for (path, origin) in roms {
+        let bytes = fs::read(path).expect("Read ROM file failed");
+        vm.map_rom(origin, &bytes).unwrap_or_else(|e| panic!("--rom {}: {}", path, e));
+    }
+    // after loading, so a poke can patch the image as well as set up data
+    for (address, value) in pokes {
+        vm.poke(address, value);
+    }
+    if let Some(program_args) = program_args {
+        vm.set_args(&program_args).unwrap_or_else(|e| panic!("{}", e));
+    }
+    // the start doesn't follow any image's origin, without --pc or --entry it's
+    // x3000 however many images are loaded and wherever they are
+    if let Some(address) = start_pc {
+        if entry.is_some() {
+            panic!("--pc and --entry both set the start, pass only one");
+        }
+        vm.write_reg(PC, address);
+    }
+    let symbols = syms.map(|path| read_symbols(path).expect("Read symbol file failed"));
+    if let Some(label) = entry {
+        let Some(symbols) = &symbols else {
+            panic!("--entry {} needs a symbol file, pass it with --syms", label);
+        };
+        let Some(&address) = symbols.get(label.as_str()) else {
+            panic!("label '{}' is not in the symbol table {}", label, syms.unwrap());
+        };
+        vm.write_reg(PC, address);
+    }
+    if coverage {
+        // only the program is tracked, not the OS
+        vm.enable_coverage(&sections.iter().map(layout).collect::<Vec<_>>());
+    }
+    if trap_times {
+        vm.enable_trap_times();
+    }
+    if branch_stats {
+        vm.enable_branch_stats();
+    }
+
+    if trace {
+        let color = color::enabled_on(no_color, &io::stderr());
+        vm.set_pre_step_hook(Box::new(move |vm, pc| match effective_access(vm, pc).filter(|_| addresses) {
+            Some(access) => eprintln!("{} {}", trace_text(vm, pc, 18, color), access),
+            None => eprintln!("{}", trace_text(vm, pc, 0, color)),
+        }));
+    }
+
+    // all need the instruction's outcome, they share the post-step hook
+    let graph = Arc::new(Mutex::new(FlowGraph::new()));
+    let binary_trace = trace_bin.map(|path| {
+        let file = BufWriter::new(File::create(path).expect("Create trace file failed"));
+        Arc::new(Mutex::new(BinaryTrace::new(file, &vm).expect("Write trace file failed")))
+    });
+    // kept for after the run, so --output is flushed and --core-dump written
+    let divergence = Arc::new(Mutex::new(None::<String>));
+    let replay = replay.map(|path| {
+        let file = BufReader::new(File::open(path).expect("Open trace file failed"));
+        Arc::new(Mutex::new(Replay::new(file, &vm).unwrap_or_else(|e| panic!("Read trace file failed: {}", e))))
+    });
+    if cfg.is_some() || follow_jumps || binary_trace.is_some() || replay.is_some() {
+        let graph = cfg.is_some().then(|| graph.clone());
+        let binary_trace = binary_trace.clone();
+        let replay = replay.clone();
+        let divergence = divergence.clone();
+        vm.set_post_step_hook(Box::new(move |vm, pc, instr| {
+            if let Some(graph) = &graph {
+                let next = vm.running().then(|| vm.read_reg(PC));
+                graph.lock().unwrap().record(pc, next);
+            }
+            if let Some(line) = jump_line(vm, pc, instr).filter(|_| follow_jumps) {
+                eprintln!("{}", line);
+            }
+            if let Some(trace) = &binary_trace {
+                trace.lock().unwrap().record(vm, pc, instr).expect("Write trace file failed");
+            }
+            // the first divergence ends the run, what follows it is noise
+            if let Some(replay) = &replay {
+                if let Err(d) = replay.lock().unwrap().check(vm, pc, instr) {
+                    divergence.lock().unwrap().get_or_insert(d);
                 }
-                print!("{}", (c as u8) as char);
-                start += 1;
             }
-            stdout().flush().expect("failed to flush");
-        }
-        // TRAP_IN
-        0x23 => {
-            print!("Enter a character: ");
-            stdout().flush().expect("failed to flush");
-            let c = read_char();
-            print!("{}", c as char);
-            stdout().flush().expect("failed to flush");
-            vm.write_reg(R0, c as u16);
-            vm.update_flags(R0);
-        }
-        // TRAP_PUTSP
-        0x24 => {
-            // Putsp
-            let mut start_address = vm.read_reg(R0);
-            loop {
-                let c = vm.read_memory(start_address);
-                if c == 0 {
+        }));
+    }
+
+    if dump {
+        dump_vectors(&vm, &mut io::stdout()).expect("failed to write");
+        return;
+    }
+
+    if let Some(n) = break_after {
+        if !debug {
+            panic!("--break-after only applies to debug");
+        }
+        // without the debugger's history, the steps before it can't be undone
+        let mut executed = 0;
+        while executed < n && vm.running() {
+            vm.step();
+            executed += 1;
+        }
+        match vm.halt_reason() {
+            Some(reason) => println!("\nprogram stopped after {} instructions: {}", executed, reason),
+            None => println!("\nstopped after {} instructions, PC = x{:04X}", executed, vm.read_reg(PC)),
+        }
+    }
+
+    if tui && !debug {
+        panic!("--tui only applies to debug");
+    }
+    if !report.is_empty() && !summary {
+        panic!("--report only applies to --summary-json");
+    }
+    if debug {
+        // the debugger reads line by line, keep the terminal in canonical mode
+        let mut debugger = Debugger::new(vm);
+        if let Some(symbols) = symbols {
+            debugger.set_symbols(symbols);
+        }
+        if tui {
+            debug_full_screen(&mut debugger);
+            return;
+        }
+        let result = if image == "-" {
+            let tty = File::open("/dev/tty").expect("debugging an image from stdin needs a terminal");
+            debugger.repl(&mut BufReader::new(tty), &mut io::stdout())
+        } else {
+            debugger.repl(&mut io::stdin().lock(), &mut io::stdout())
+        };
+        result.expect("debugger failed");
+        return;
+    }
+
+    if let Some(fd) = keyboard {
+        setup_terminal(fd);
+    }
+    let stop_when: Option<StopWhen> = match (until, &replay) {
+        (None, None) => None,
+        _ => {
+            let divergence = divergence.clone();
+            Some(Box::new(move |vm| {
+                divergence.lock().unwrap().is_some() || until.is_some_and(|(address, value)| vm.peek(address) == value)
+            }))
+        }
+    };
+    // begin fetch instruction and execute instruction
+    let start = Instant::now();
+    let vm = match timeout {
+        None => {
+            execute(&mut vm, stop_when);
+            vm
+        }
+        Some(limit) => run_with_timeout(vm, limit, stop_when),
+    };
+    let elapsed = start.elapsed();
+    let divergence = divergence.lock().unwrap().take();
+    match vm.halt_reason() {
+        // reported with the replay below
+        _ if divergence.is_some() => {}
+        None => {
+            let (address, value) = until.expect("the VM only returns running at the --run-until-mem value");
+            println!("\nstopped: x{:04X} holds x{:04X}", address, value);
+        }
+        Some(HaltReason::WriteProtected(a)) => println!("stopped: write to protected address x{:04X}", a),
+        Some(HaltReason::OutputLimit(n)) => println!("\nstopped: output limit of {} bytes reached", n),
+        Some(HaltReason::CycleLimit(n)) => println!("\nstopped: cycle limit of {} reached", n),
+        Some(HaltReason::InfiniteLoop { pc, period }) => {
+            println!("\nstopped: likely infinite loop, the whole state at x{:04X} recurs every {} instructions", pc, period)
+        }
+        Some(HaltReason::NullWrite { address, pc }) => {
+            println!("\nstopped: store at x{:04X} to x{:04X}, inside the null guard", pc, address)
+        }
+        Some(HaltReason::IllegalOpcode { address, word }) => {
+            println!("stopped: illegal opcode x{:04X} at x{:04X}", word, address);
+            for a in address.saturating_sub(3)..=address.saturating_add(2) {
+                let marker = if a == address { "=>" } else { "  " };
+                println!("{} x{:04X}  x{:04X}  {}", marker, a, vm.peek(a), disassemble(vm.fetch(a), a));
+            }
+        }
+        Some(HaltReason::InputExhausted) => println!("\nstopped: input exhausted"),
+        // --quiet leaves stdout to the program when it halts as expected
+        Some(HaltReason::Halt) if quiet => {}
+        Some(HaltReason::Halt) => println!("HALT!"),
+    }
+    if let Some(trace) = binary_trace {
+        trace.lock().unwrap().flush().expect("Write trace file failed");
+    }
+    // however the run ended, HALT, an error, --run-until-mem or a divergence
+    if let Some(path) = core_dump {
+        fs::write(path, vm.export_region(0, MR_KBSR as usize).to_bytes()).expect("Write core dump failed");
+    }
+    if let Some(replay) = replay {
+        match divergence.map_or_else(|| replay.lock().unwrap().finish(), Err) {
+            Ok(steps) => println!("replay: all {} instructions match the recording", steps),
+            Err(divergence) => println!("\nREPLAY: {}", divergence),
+        }
+    }
+    if let Some(cycles) = vm.cycles() {
+        println!("{} cycles for {} instructions", cycles, vm.instructions());
+    }
+    if stats {
+        let rate = vm.instructions() as f64 / elapsed.as_secs_f64();
+        println!("{} instructions in {:.3}s, {:.0} instructions/s", vm.instructions(), elapsed.as_secs_f64(), rate);
+    }
+    if let Some(coverage) = vm.coverage() {
+        coverage.report(&mut io::stdout()).expect("failed to write");
+    }
+    if let Some(branches) = vm.branch_stats() {
+        branches.report(&vm, &mut io::stdout()).expect("failed to write");
+    }
+    if let Some(times) = vm.trap_times() {
+        times.report(&mut io::stdout()).expect("failed to write");
+    }
+    if let Some(path) = cfg {
+        let mut file = File::create(path).expect("Create graph file failed");
+        graph.lock().unwrap().write_dot(&vm, &mut file).expect("Write graph file failed");
+    }
+    // last, so a script can take the final line
+    if summary {
+        println!("{}", summary_json(&vm, &report));
+    }
+    process::exit(1);
+}
+
+// checked after each step, true ends the run with the VM still running
+type StopWhen = Box<dyn FnMut(&VM) -> bool + Send>;
+
+// run until the program stops, or until stop_when says to
+fn execute(vm: &mut VM, stop_when: Option<StopWhen>) {
+    match stop_when {
+        None => {
+            vm.run();
+        }
+        Some(mut stop_when) => {
+            while matches!(vm.step(), Control::Continue | Control::Interrupt) {
+                if stop_when(vm) {
                     break;
                 }
-                // low 8 bits to char
-                let c1 = ((c & 0xFF) as u8) as char;
-                print!("{}", c1);
-                // high 8 bits to char
-                let c2 = ((c >> 8) as u8) as char;
-                if c2 != '\0' {
-                    print!("{}", c2);
+            }
+        }
+    }
+    // the process exits without dropping the VM, write out an --output file
+    vm.flush();
+}
+
+// run the VM on a thread and give up on it after limit. a program blocked
+// reading the keyboard can't be interrupted, so the whole process exits
+fn run_with_timeout(mut vm: VM, limit: Duration, stop_when: Option<StopWhen>) -> VM {
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        execute(&mut vm, stop_when);
+        let _ = done.send(vm);
+    });
+    match finished.recv_timeout(limit) {
+        Ok(vm) => vm,
+        Err(_) => {
+            println!("TIMEOUT: still running after {} seconds", limit.as_secs());
+            process::exit(2);
+        }
+    }
+}
+
+// describe the image's sections and warn about any in reserved memory, without running it
+fn info_image(args: &[String]) {
+    let [path] = args else {
+        panic!("{}", USAGE);
+    };
+    let sections = match read_sections(path) {
+        Ok(sections) => sections,
+        Err(e) => {
+            println!("{}: invalid image: {}", path, e);
+            process::exit(1);
+        }
+    };
+    let total: usize = sections.iter().map(|s| s.words.len()).sum();
+    println!("{}: {} section(s), {} words", path, sections.len(), total);
+    let mut warnings = Vec::new();
+    for s in &sections {
+        if s.words.is_empty() {
+            println!("  x{:04X}  empty", s.origin);
+            continue;
+        }
+        let last = (s.end() - 1) as u16;
+        println!("  x{:04X}-x{:04X}  {} words", s.origin, last, s.words.len());
+        if last >= MR_KBSR {
+            warnings.push(format!("x{:04X}-x{:04X} is in the device registers at xFE00-xFFFF", s.origin.max(MR_KBSR), last));
+        }
+        if s.origin < 0x0200 {
+            warnings.push(format!("x{:04X}-x{:04X} is in the trap and interrupt vector tables at x0000-x01FF", s.origin, last.min(0x01ff)));
+        } else if s.origin < PC_START {
+            warnings.push(format!("x{:04X} is in system space below x3000", s.origin));
+        }
+    }
+    if let Some(s) = sections.first().filter(|s| s.origin != PC_START) {
+        warnings.push(format!("execution starts at x{:04X}, the first section is at x{:04X}", PC_START, s.origin));
+    }
+    for w in &warnings {
+        println!("warning: {}", w);
+    }
+}
+
+// report every problem with an object file's format, exiting nonzero if there are any
+fn check_image(args: &[String]) {
+    let [path] = args else {
+        panic!("{}", USAGE);
+    };
+    let bytes = fs::read(path).expect("Read image file failed");
+    let problems = check_bytes(&bytes);
+    if problems.is_empty() {
+        println!("{}: ok", path);
+        return;
+    }
+    for p in &problems {
+        println!("{}: {}", path, p);
+    }
+    process::exit(1);
+}
+
+// print which ranges of memory the images fill with code and data
+fn map_image(args: &[String]) {
+    let mut args = args.iter();
+    let mut vm = VM::new();
+    let mut symbols = Symbols::new();
+    let mut image = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--os" => read_image(args.next().expect(USAGE), &mut vm).expect("Read OS image file failed"),
+            "--syms" => symbols = read_symbols(args.next().expect(USAGE)).expect("Read symbol file failed"),
+            _ if image.is_none() => image = Some(arg),
+            _ => panic!("{}", USAGE),
+        }
+    }
+    read_image(image.expect(USAGE), &mut vm).expect("Read image file failed");
+    memory_map(&vm, &symbols, &mut io::stdout()).expect("failed to write");
+}
+
+// assemble a source file to an object file, next to it unless -o is given
+fn assemble_file(args: &[String]) {
+    let mut args = args.iter();
+    let mut source = None;
+    let mut out = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => out = Some(args.next().expect(USAGE).clone()),
+            _ if source.is_none() => source = Some(arg),
+            _ => panic!("{}", USAGE),
+        }
+    }
+    let source = source.expect(USAGE);
+    let out = out.unwrap_or_else(|| format!("{}.obj", source.strip_suffix(".asm").unwrap_or(source)));
+    let text = fs::read_to_string(source).expect("Read source file failed");
+    match assemble(&text) {
+        Ok(program) => fs::write(&out, program.to_bytes()).expect("Write object file failed"),
+        Err(e) => {
+            println!("{}: {}", source, e);
+            process::exit(1);
+        }
+    }
+}
+
+// resolve the objects' external labels against each other and write one image
+fn link_objects(args: &[String]) {
+    let mut args = args.iter();
+    let mut objects = Vec::new();
+    let mut out = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => out = Some(args.next().expect(USAGE)),
+            _ => {
+                let bytes = fs::read(arg).expect("Read object file failed");
+                match Object::from_bytes(&bytes) {
+                    Ok(object) => objects.push(object),
+                    Err(e) => {
+                        println!("{}: invalid object: {}", arg, e);
+                        process::exit(1);
+                    }
                 }
-                start_address += 1;
             }
-            stdout().flush().expect("failed to flush");
         }
-        // TRAP_HALT
-        0x25 => {
-            println!("HALT!");
-            stdout().flush().expect("failed to flush");
+    }
+    let (Some(out), false) = (out, objects.is_empty()) else {
+        panic!("{}", USAGE);
+    };
+    match link(&objects) {
+        Ok(sections) => fs::write(out, sections_to_bytes(&sections)).expect("Write image file failed"),
+        Err(e) => {
+            println!("link failed: {}", e);
             process::exit(1);
         }
-        _ => {}
     }
 }
 
+// a window of text around offset with control characters escaped
+fn context(text: &[u8], offset: usize) -> String {
+    let start = offset.saturating_sub(20);
+    let end = (offset + 20).min(text.len());
+    text[start..end].escape_ascii().to_string()
+}
+
+// run the image headless on the input and compare its output with the expected file
+fn verify_image(args: &[String]) {
+    let mut args = args.iter();
+    let mut image = None;
+    let mut input = None;
+    let mut expect = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--input" => input = Some(args.next().expect(USAGE)),
+            "--expect" => expect = Some(args.next().expect(USAGE)),
+            _ if image.is_none() => image = Some(arg),
+            _ => panic!("{}", USAGE),
+        }
+    }
+    let (Some(image), Some(expect)) = (image, expect) else {
+        panic!("{}", USAGE);
+    };
+    let image = fs::read(image).expect("Read image file failed");
+    let input = input.map_or_else(Vec::new, |path| fs::read(path).expect("Read input file failed"));
+    let expected = fs::read(expect).expect("Read expected output failed");
+
+    let (report, status) = verify(run_headless(&image, &input), &expected);
+    println!("{}", report);
+    if status != 0 {
+        process::exit(status);
+    }
+}
+
+// the report on a run's output against the expected output, with the exit
+// status: 0 on a match, 1 when the output differs, 2 when the run failed
+fn verify(run: Result<Vec<u8>, Error>, expected: &[u8]) -> (String, i32) {
+    let actual = match run {
+        Ok(output) => output,
+        Err(e) => return (format!("FAIL: {}", e), 2),
+    };
+    let offset = actual.iter().zip(expected).take_while(|(a, e)| a == e).count();
+    if offset == actual.len() && offset == expected.len() {
+        return ("PASS".to_string(), 0);
+    }
+    let report = format!(
+        "FAIL: output differs at byte {} ({} bytes expected, {} produced)\nexpected: \"{}\"\nactual:   \"{}\"",
+        offset,
+        expected.len(),
+        actual.len(),
+        context(expected, offset),
+        context(&actual, offset)
+    );
+    (report, 1)
+}
+
+// render a binary trace written by --trace-bin as text
+fn decode_trace(args: &[String]) {
+    let [path] = args else {
+        panic!("{}", USAGE);
+    };
+    let mut file = BufReader::new(File::open(path).expect("Read trace file failed"));
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    decode_binary(&mut file, &mut out).expect("Decode trace file failed");
+}
 
 fn main() {
-    // terminal setup reference https://github.com/digorithm/LC-3-Rust
-    // Some tricks to make the VM's terminal be interactive
-    let stdin = 0;
-    let termios = Termios::from_fd(stdin).unwrap();
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("diff") => diff_images(&args[1..]),
+        Some("disasm") => disasm_image(&args[1..]),
+        Some("verify") => verify_image(&args[1..]),
+        Some("map") => map_image(&args[1..]),
+        Some("info") => info_image(&args[1..]),
+        Some("check") => check_image(&args[1..]),
+        Some("asm") => assemble_file(&args[1..]),
+        Some("link") => link_objects(&args[1..]),
+        Some("trace-decode") => decode_trace(&args[1..]),
+        Some("opcodes") => opcode_table(&mut io::stdout()).expect("failed to write"),
+        Some("repl") => Repl::new().run(&mut io::stdin().lock(), &mut io::stdout()).expect("repl failed"),
+        Some("debug") => run(&args[1..], true),
+        _ => run(&args, false),
+    }
+}
 
-    // make a mutable copy of termios
-    // that we will modify
-    let mut new_termios = termios.clone();
-    new_termios.c_iflag &= IGNBRK | BRKINT | PARMRK | ISTRIP | INLCR | IGNCR | ICRNL | IXON;
-    new_termios.c_lflag &= !(ICANON | ECHO); // no echo and canonical mode
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    tcsetattr(stdin, TCSANOW, &mut new_termios).unwrap();
+    fn image(src: &str) -> Vec<u8> {
+        assemble(src).unwrap().to_bytes()
+    }
 
-    let args: Vec<String> = env::args().collect();
-    let mut vm = VM::new();
+    const ECHO: &str = ".ORIG x3000\nGETC\nOUT\nHALT\n.END\n";
 
-    if args.len() != 2 {
-        panic!("lc3 [image-file]")
+    #[test]
+    fn verify_passes_on_the_expected_output() {
+        assert_eq!(verify(run_headless(&image(ECHO), b"a"), b"a"), ("PASS".to_string(), 0));
     }
 
-    // load instructions to memory from the give image file
-    read_image(&args[1], &mut vm).expect("Read image file failed");
+    #[test]
+    fn verify_fails_on_different_output() {
+        let (report, status) = verify(run_headless(&image(ECHO), b"b"), b"a\n");
+        assert_eq!(status, 1);
+        assert_eq!(report, "FAIL: output differs at byte 0 (2 bytes expected, 1 produced)\nexpected: \"a\\n\"\nactual:   \"b\"");
+    }
 
-    // begin fetch instruction and execute instruction
-    loop {
-        let pc_val = vm.read_pc();
-        vm.add_pc();
-        let instr = vm.read_memory(pc_val);
-        let op = get_op(instr >> 12);
-        match op {
-            Op::BR => br(&mut vm, instr),
-            Op::ADD => add(&mut vm, instr),
-            Op::LD => ld(&mut vm, instr),
-            Op::ST => st(&mut vm, instr),
-            Op::JSR => jsr(&mut vm, instr),
-            Op::AND => and(&mut vm, instr),
-            Op::LDR => ldr(&mut vm, instr),
-            Op::STR => str(&mut vm, instr),
-            Op::NOT => not(&mut vm, instr),
-            Op::LDI => ldi(&mut vm, instr),
-            Op::STI => sti(&mut vm, instr),
-            Op::JMP => jmp(&mut vm, instr),
-            Op::LEA => lea(&mut vm, instr),
-            Op::TRAP => trap(&mut vm, instr),
-            _ => {}
-        }
+    #[test]
+    fn verify_fails_when_the_run_does() {
+        let (report, status) = verify(run_headless(&image(ECHO), b""), b"a");
+        assert_eq!(status, 2);
+        assert_eq!(report, "FAIL: program read past the end of its input");
     }
 }
-
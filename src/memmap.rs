@@ -0,0 +1,57 @@
+use std::io;
+use std::io::Write;
+use crate::symbols::Symbols;
+use crate::vm::{get_op, Op, MEMORY_MAX, MR_KBSR, VM};
+
+// a compact map of memory: the vector tables, the loaded words split into
+// code and data, and the device registers
+
+// the vector tables end here, the loaded words below it belong to them
+const VECTORS_END: u16 = 0x0200;
+
+// a guess: zero, printable ASCII (which decodes as a never-taken branch),
+// a branch with no condition and the reserved opcode are data, the rest code
+fn is_data(word: u16) -> bool {
+    let op = get_op(word >> 12);
+    word == 0 || (0x20..0x7f).contains(&word) || (matches!(op, Op::BR) && word & 0x0e00 == 0) || matches!(op, Op::RES)
+}
+
+fn labels(symbols: &Symbols, start: u16, end: u16) -> String {
+    let inside: Vec<&str> = symbols.iter().filter(|(_, &a)| (start..=end).contains(&a)).map(|(n, _)| n.as_str()).collect();
+    if inside.is_empty() {
+        String::new()
+    } else {
+        format!("  {}", inside.join(", "))
+    }
+}
+
+pub fn memory_map(vm: &VM, symbols: &Symbols, out: &mut dyn Write) -> io::Result<()> {
+    let mut loaded = vec![false; MEMORY_MAX];
+    for &(origin, len) in vm.loaded_ranges() {
+        loaded[origin as usize..origin as usize + len].fill(true);
+    }
+    for (start, title) in [(0x0000u16, "trap vector table"), (0x0100, "interrupt vector table")] {
+        let set = (start..start + 0x100).filter(|&v| vm.peek(v) != 0).count();
+        writeln!(out, "x{:04X}-x{:04X}  {:<22} {} entries set", start, start + 0xff, title, set)?;
+    }
+    let mut address = VECTORS_END;
+    while address < MR_KBSR {
+        if !loaded[address as usize] {
+            address += 1;
+            continue;
+        }
+        let data = is_data(vm.peek(address));
+        let start = address;
+        while address < MR_KBSR && loaded[address as usize] && is_data(vm.peek(address)) == data {
+            address += 1;
+        }
+        let end = address - 1;
+        let kind = if data { "data" } else { "code" };
+        let words = match end - start + 1 {
+            1 => "1 word".to_string(),
+            n => format!("{} words", n),
+        };
+        writeln!(out, "x{:04X}-x{:04X}  {:<22} {}{}", start, end, kind, words, labels(symbols, start, end))?;
+    }
+    writeln!(out, "x{:04X}-xFFFF  device registers", MR_KBSR)
+}
@@ -0,0 +1,82 @@
+use std::io;
+use std::io::{BufRead, Write};
+use crate::assembler::assemble_one;
+use crate::vm::RegisterType::PC;
+use crate::vm::{REGISTER_NAMES, VM};
+
+const HELP: &str = "\
+INSTRUCTION       assemble one instruction at PC and execute it, e.g. ADD R0, R0, #1
+.regs             show the registers
+.reset            start over with a fresh VM
+.quit             quit
+";
+
+// a calculator for LC-3: each line is assembled at PC and executed against
+// a VM that lives for the whole session, then the registers it changed are shown
+pub struct Repl {
+    pub vm: VM,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Repl::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Repl { vm: VM::new() }
+    }
+
+    // read lines until .quit or the input ends
+    pub fn run(&mut self, input: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<()> {
+        let mut line = String::new();
+        loop {
+            write!(out, "lc3> ")?;
+            out.flush()?;
+            line.clear();
+            if input.read_line(&mut line)? == 0 || !self.line(&line, out)? {
+                return Ok(());
+            }
+        }
+    }
+
+    // handle a single line, returns false when the user quits
+    pub fn line(&mut self, line: &str, out: &mut dyn Write) -> io::Result<bool> {
+        match line.trim() {
+            "" => {}
+            ".quit" | ".q" => return Ok(false),
+            ".help" => write!(out, "{}", HELP)?,
+            ".regs" => self.vm.dump_registers(out)?,
+            ".reset" => self.vm = VM::new(),
+            text => self.execute(text, out)?,
+        }
+        Ok(true)
+    }
+
+    fn execute(&mut self, text: &str, out: &mut dyn Write) -> io::Result<()> {
+        if let Some(reason) = self.vm.halt_reason() {
+            return writeln!(out, "the VM stopped: {}, .reset to start over", reason);
+        }
+        if text.starts_with('.') {
+            return writeln!(out, "unknown command '{}', try .help", text);
+        }
+        let pc = self.vm.read_reg(PC);
+        let word = match assemble_one(pc, text) {
+            Ok(word) => word,
+            Err(e) => return writeln!(out, "{}", e),
+        };
+        let before = self.vm.registers().to_vec();
+        self.vm.poke(pc, word);
+        self.vm.step();
+        for (i, (old, new)) in before.iter().zip(self.vm.registers()).enumerate() {
+            if old != new {
+                writeln!(out, "{:<8} x{:04X} -> x{:04X} {:>6}", REGISTER_NAMES[i], old, new, *new as i16)?;
+            }
+        }
+        if let Some(reason) = self.vm.halt_reason() {
+            writeln!(out, "stopped: {}", reason)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+use std::io;
+use std::io::Write;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use crate::assembler::assemble;
+use crate::vm::{Control, HaltReason, VM};
+use crate::Error;
+
+// headless runs give up after this many instructions
+pub const STEP_LIMIT: u64 = 10_000_000;
+
+// an output stream that keeps everything written to it
+#[derive(Clone, Default)]
+pub struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Capture {
+    pub fn bytes(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+
+    // the bytes written since the last take, leaving the capture empty
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+
+    // how many bytes are held
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // drop everything written after the first len bytes
+    pub fn truncate(&self, len: usize) {
+        self.0.lock().unwrap().truncate(len);
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// how a headless run ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    // the VM stopped on its own
+    Halted(HaltReason),
+    // the program didn't halt within the step limit
+    StepLimit,
+    // the image could not be loaded
+    LoadFailed(String),
+}
+
+// what a headless run produced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunResult {
+    // the bytes the program wrote, as written
+    pub output: Vec<u8>,
+    pub instructions: u64,
+    pub stop: Stop,
+}
+
+// load the image into a fresh VM, feed it the input and run it without a terminal
+fn run_image(image: &[u8], input: &[u8]) -> RunResult {
+    let capture = Capture::default();
+    let mut vm = VM::with_io(Box::new(io::Cursor::new(input.to_vec())), Box::new(capture.clone()));
+    if let Err(e) = vm.load_bytes(image) {
+        return RunResult { output: Vec::new(), instructions: 0, stop: Stop::LoadFailed(e.to_string()) };
+    }
+    let mut instructions = 0;
+    let stop = loop {
+        if instructions == STEP_LIMIT {
+            break Stop::StepLimit;
+        }
+        instructions += 1;
+        match vm.step() {
+            Control::Continue | Control::Interrupt => {}
+            Control::Halt => break Stop::Halted(HaltReason::Halt),
+            Control::Error(reason) => break Stop::Halted(reason),
+        }
+    };
+    RunResult { output: capture.bytes(), instructions, stop }
+}
+
+// run the image until HALT without a terminal, returning everything the program printed
+pub fn run_headless(image: &[u8], input: &[u8]) -> Result<Vec<u8>, Error> {
+    let result = run_image(image, input);
+    match result.stop {
+        Stop::Halted(HaltReason::Halt) => Ok(result.output),
+        Stop::Halted(HaltReason::InputExhausted) => Err(Error::InputExhausted),
+        Stop::Halted(HaltReason::WriteProtected(a)) => Err(Error::WriteProtected(a)),
+        Stop::Halted(HaltReason::IllegalOpcode { address, word }) => Err(Error::IllegalOpcode { address, word }),
+        Stop::Halted(HaltReason::OutputLimit(n)) => Err(Error::OutputLimit(n)),
+        Stop::Halted(HaltReason::CycleLimit(n)) => Err(Error::StepLimit(n)),
+        Stop::Halted(HaltReason::NullWrite { address, pc }) => Err(Error::NullWrite { address, pc }),
+        Stop::Halted(HaltReason::InfiniteLoop { pc, period }) => Err(Error::InfiniteLoop { pc, period }),
+        Stop::StepLimit => Err(Error::StepLimit(STEP_LIMIT)),
+        Stop::LoadFailed(e) => Err(Error::Load(io::Error::new(io::ErrorKind::InvalidData, e))),
+    }
+}
+
+// run_headless for a program that prints text, its output has to be valid UTF-8
+pub fn run_headless_utf8(image: &[u8], input: &[u8]) -> Result<String, Error> {
+    String::from_utf8(run_headless(image, input)?).map_err(Error::Utf8)
+}
+
+// run each image in its own VM with the same input, a failing image
+// is reported in its result and doesn't affect the others
+pub fn run_batch(images: &[&[u8]], input: &[u8]) -> Vec<RunResult> {
+    images.iter().map(|image| run_image(image, input)).collect()
+}
+
+// assemble the source, then run it headless with the given input, the output as text
+pub fn assemble_and_run(src: &str, input: &[u8]) -> Result<String, Error> {
+    let program = assemble(src)?;
+    run_headless_utf8(&program.to_bytes(), input)
+}
+
+// step the VMs round-robin, quantum instructions each, until all of them
+// have stopped or they've run limit instructions between them. the shared
+// window is mirrored: after each VM's turn its copy is written to every other
+// VM, so a store there is seen by the VMs running after it. turns always go
+// in slice order, a run is reproducible
+pub fn run_cooperative(vms: &mut [VM], shared: Range<u16>, quantum: usize, limit: u64) -> Result<(), Error> {
+    if quantum == 0 {
+        return Err(Error::ZeroQuantum);
+    }
+    let mut steps = 0;
+    while vms.iter().any(VM::running) {
+        for i in 0..vms.len() {
+            if !vms[i].running() {
+                continue;
+            }
+            for _ in 0..quantum {
+                if steps == limit {
+                    return Err(Error::StepLimit(limit));
+                }
+                steps += 1;
+                if !matches!(vms[i].step(), Control::Continue | Control::Interrupt) {
+                    break;
+                }
+            }
+            for address in shared.clone() {
+                let word = vms[i].peek(address);
+                for (j, other) in vms.iter_mut().enumerate() {
+                    if j != i && other.peek(address) != word {
+                        other.poke(address, word);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assemble_and_run_returns_the_output() {
+        let src = ".ORIG x3000\nGETC\nOUT\nLEA R0, MSG\nPUTS\nHALT\nMSG .STRINGZ \"k\"\n.END\n";
+        assert_eq!(assemble_and_run(src, b"o").unwrap(), "ok");
+    }
+
+    #[test]
+    fn assemble_and_run_reports_an_assembler_error() {
+        let src = ".ORIG x3000\nHALT\nFROB R0\n.END\n";
+        match assemble_and_run(src, b"") {
+            Err(Error::Assemble(e)) => assert_eq!(e.line, 3, "{}", e),
+            other => panic!("expected an assembler error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn assemble_and_run_reports_exhausted_input() {
+        let src = ".ORIG x3000\nGETC\nGETC\nHALT\n.END\n";
+        assert!(matches!(assemble_and_run(src, b"x"), Err(Error::InputExhausted)));
+    }
+
+    // three times: bump the shared counter at x4000 and print it as a digit
+    const COUNTER: &str = "
+        .ORIG x3000
+              AND R1, R1, #0
+              ADD R1, R1, #3
+        LOOP  LDI R0, PTR
+              ADD R0, R0, #1
+              STI R0, PTR
+              LD R2, ZERO
+              ADD R0, R0, R2
+              OUT
+              ADD R1, R1, #-1
+              BRp LOOP
+              HALT
+        PTR   .FILL x4000
+        ZERO  .FILL x30
+        .END";
+
+    fn machines(sources: &[&str]) -> (Vec<VM>, Vec<Capture>) {
+        sources
+            .iter()
+            .map(|src| {
+                let capture = Capture::default();
+                let mut vm = VM::with_io(Box::new(io::empty()), Box::new(capture.clone()));
+                vm.load_bytes(&assemble(src).unwrap().to_bytes()).unwrap();
+                (vm, capture)
+            })
+            .unzip()
+    }
+
+    #[test]
+    fn cooperative_run_is_reproducible() {
+        let run = || {
+            let (mut vms, captures) = machines(&[COUNTER, COUNTER]);
+            run_cooperative(&mut vms, 0x4000..0x4001, 3, STEP_LIMIT).unwrap();
+            assert!(vms.iter().all(|vm| vm.halt_reason() == Some(HaltReason::Halt)));
+            captures.iter().map(|c| String::from_utf8(c.bytes()).unwrap()).collect::<Vec<_>>()
+        };
+        let outputs = run();
+        // turns end between a load of the counter and its store, so the VMs
+        // overwrite each other's counts, the same way every run
+        assert_eq!(outputs, ["123", "124"]);
+        for _ in 0..4 {
+            assert_eq!(run(), outputs);
+        }
+    }
+
+    #[test]
+    fn cooperative_run_refuses_a_zero_quantum_and_stops_at_the_limit() {
+        let (mut vms, _) = machines(&[COUNTER]);
+        assert!(matches!(run_cooperative(&mut vms, 0x4000..0x4001, 0, STEP_LIMIT), Err(Error::ZeroQuantum)));
+        // BR #-1 forever next to a program that halts
+        let (mut vms, _) = machines(&[".ORIG x3000\nBR #-1\n.END", COUNTER]);
+        assert!(matches!(run_cooperative(&mut vms, 0x4000..0x4001, 5, 1000), Err(Error::StepLimit(1000))));
+        assert!(vms[0].running());
+        assert!(!vms[1].running());
+    }
+}
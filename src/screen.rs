@@ -0,0 +1,86 @@
+// a non-standard text screen for programs that want more than a stream of
+// characters: the program sets a cursor through two registers and places a
+// character there through a third, the grid is kept in memory and every
+// change is drawn on the terminal with ANSI escapes
+
+// the cursor row and column, a write moves the cursor
+pub const SCR_ROW: u16 = 0xFE10;
+pub const SCR_COL: u16 = 0xFE12;
+// a write places the character at the cursor and moves it one column right
+pub const SCR_DATA: u16 = 0xFE14;
+// writing SCR_CLEAR blanks the screen and homes the cursor
+pub const SCR_CTRL: u16 = 0xFE16;
+pub const SCR_CLEAR: u16 = 1;
+
+pub const ROWS: usize = 24;
+pub const COLS: usize = 80;
+
+pub struct Screen {
+    cells: Vec<u8>,
+    row: usize,
+    col: usize,
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Screen::new()
+    }
+}
+
+impl Screen {
+    pub fn new() -> Screen {
+        Screen { cells: vec![b' '; ROWS * COLS], row: 0, col: 0 }
+    }
+
+    // is address one of the screen's registers
+    pub fn handles(address: u16) -> bool {
+        matches!(address, SCR_ROW | SCR_COL | SCR_DATA | SCR_CTRL)
+    }
+
+    // apply a register write, returning what to send to the terminal to show it.
+    // the cursor is clamped to the grid, a character past the last column wraps
+    pub fn write(&mut self, address: u16, val: u16) -> Vec<u8> {
+        match address {
+            SCR_ROW => self.row = (val as usize).min(ROWS - 1),
+            SCR_COL => self.col = (val as usize).min(COLS - 1),
+            SCR_DATA => {
+                let c = val as u8;
+                self.cells[self.row * COLS + self.col] = c;
+                let drawn = format!("\x1b[{};{}H", self.row + 1, self.col + 1);
+                self.col += 1;
+                if self.col == COLS {
+                    self.col = 0;
+                    self.row = (self.row + 1) % ROWS;
+                }
+                let mut bytes = drawn.into_bytes();
+                bytes.push(c);
+                return bytes;
+            }
+            SCR_CTRL if val == SCR_CLEAR => {
+                self.cells.fill(b' ');
+                self.row = 0;
+                self.col = 0;
+                return b"\x1b[2J\x1b[H".to_vec();
+            }
+            _ => {}
+        }
+        Vec::new()
+    }
+
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, self.col)
+    }
+
+    // the character at row and column
+    pub fn get(&self, row: usize, col: usize) -> u8 {
+        self.cells[row * COLS + col]
+    }
+
+    // the grid as text, a line per row with trailing blanks trimmed
+    pub fn text(&self) -> String {
+        self.cells
+            .chunks(COLS)
+            .map(|row| String::from_utf8_lossy(row).trim_end().to_string() + "\n")
+            .collect()
+    }
+}
@@ -0,0 +1,49 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+// symbol files, in the format lc3as writes:
+//
+// // Symbol table
+// // Scope level 0:
+// //	Symbol Name       Page Address
+// //	----------------  ------------
+// //	MAIN              3000
+//
+// a line with a label and a hex address, with or without the leading //
+// and an x, is a symbol, everything else is ignored
+
+pub type Symbols = BTreeMap<String, u16>;
+
+fn is_label(token: &str) -> bool {
+    token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+pub fn parse_symbols(text: &str) -> Symbols {
+    let mut symbols = Symbols::new();
+    for line in text.lines() {
+        let line = line.trim_start().trim_start_matches('/');
+        let [name, address] = line.split_whitespace().collect::<Vec<_>>()[..] else {
+            continue;
+        };
+        let hex = address.trim_start_matches(['x', 'X']);
+        if let (true, Ok(address)) = (is_label(name), u16::from_str_radix(hex, 16)) {
+            symbols.insert(name.to_string(), address);
+        }
+    }
+    symbols
+}
+
+pub fn read_symbols(path: &str) -> io::Result<Symbols> {
+    Ok(parse_symbols(&fs::read_to_string(path)?))
+}
+
+// the symbol table as lc3as would write it
+pub fn format_symbols(symbols: &Symbols) -> String {
+    let mut text = String::from("// Symbol table\n// Scope level 0:\n//\tSymbol Name       Page Address\n//\t----------------  ------------\n");
+    for (name, address) in symbols {
+        text.push_str(&format!("//\t{:<16}  {:04X}\n", name, address));
+    }
+    text
+}
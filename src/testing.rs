@@ -0,0 +1,87 @@
+use crate::vm::{Flags, RegisterType, VM};
+use crate::vm::RegisterType::COND;
+
+// assertions for tests driving a VM, on a mismatch they panic showing all the registers
+
+// a condition code, as set in COND
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    N,
+    Z,
+    P,
+}
+
+impl Flag {
+    pub fn bits(self) -> u16 {
+        match self {
+            Flag::N => Flags::N,
+            Flag::Z => Flags::Z,
+            Flag::P => Flags::P,
+        }
+    }
+}
+
+fn dump(vm: &VM) -> String {
+    let mut out = Vec::new();
+    vm.dump_registers(&mut out).expect("writing to a Vec can't fail");
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// every listed register holds its expected value
+#[track_caller]
+pub fn assert_regs(vm: &VM, expected: &[(RegisterType, u16)]) {
+    for &(reg, want) in expected {
+        let got = vm.read_reg(reg);
+        assert!(got == want, "{:?} is x{:04X}, expected x{:04X}\n{}", reg, got, want, dump(vm));
+    }
+}
+
+// COND holds exactly the given flag
+#[track_caller]
+pub fn assert_cond(vm: &VM, flag: Flag) {
+    let cond = vm.read_reg(COND);
+    assert!(cond == flag.bits(), "COND is x{:04X}, expected {:?}\n{}", cond, flag, dump(vm));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use super::*;
+    use crate::vm::RegisterType::{R0, R1};
+
+    fn vm() -> VM {
+        let mut vm = VM::with_io(Box::new(io::empty()), Box::new(io::sink()));
+        vm.write_reg(R0, 5);
+        vm.write_reg(R1, 0xFFFF);
+        vm.set_cond_flags(Flags::of(0xFFFF));
+        vm
+    }
+
+    #[test]
+    fn matching_state_passes() {
+        let vm = vm();
+        assert_regs(&vm, &[(R0, 5), (R1, 0xFFFF)]);
+        assert_cond(&vm, Flag::N);
+    }
+
+    #[test]
+    #[should_panic(expected = "R1 is xFFFF, expected x0001")]
+    fn register_mismatch_panics() {
+        assert_regs(&vm(), &[(R0, 5), (R1, 1)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "COND is x0004, expected P")]
+    fn cond_mismatch_panics() {
+        assert_cond(&vm(), Flag::P);
+    }
+
+    #[test]
+    fn flags_match_cond() {
+        let mut vm = vm();
+        for (val, flag) in [(0x8000, Flag::N), (0, Flag::Z), (1, Flag::P)] {
+            vm.set_cond_flags(Flags::of(val));
+            assert_cond(&vm, flag);
+        }
+    }
+}
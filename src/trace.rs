@@ -0,0 +1,326 @@
+use std::io;
+use std::io::{Read, Write};
+use crate::disasm::disassemble;
+use crate::vm::RegisterType::PC;
+use crate::vm::{decode, get_op, Op, REGISTER_NAMES, REG_COUNT, VM};
+
+// leads a binary trace, followed by a record per instruction executed: its
+// PC, its word and a mask with bit i set for every register i (R0-R7 and
+// COND as 9) it changed, then the new value of each of those registers in
+// order, all big-endian. PC changes every step and is left out, it's the
+// next record's PC. a record is 6 bytes plus 2 per changed register
+pub const TRACE_MAGIC: [u8; 4] = *b"LC3T";
+
+// the instruction about to execute at pc, as address, word and disassembly
+pub fn trace_line(vm: &VM, pc: u16) -> String {
+    let instr = vm.fetch(pc);
+    format!("x{:04X}  x{:04X}  {}", pc, instr, disassemble(instr, pc))
+}
+
+// where a memory instruction at pc is about to read or write and the value
+// moved, for LDI/STI both the pointer and its target. computed before the
+// instruction executes so a load into its own base register is still right.
+// device registers are shown as peeked, without reading the device
+pub fn effective_access(vm: &VM, pc: u16) -> Option<String> {
+    let d = decode(vm.fetch(pc));
+    let pc_relative = pc.wrapping_add(1).wrapping_add(d.pc_offset9 as u16);
+    let base_relative = vm.read_reg_by_index(d.base_r).wrapping_add(d.offset6 as u16);
+    let access = |address: u16, value: u16| format!("[x{:04X}] = x{:04X}", address, value);
+    let stored = vm.read_reg_by_index(d.dr);
+    match d.op {
+        Op::LD => Some(access(pc_relative, vm.peek(pc_relative))),
+        Op::LDR => Some(access(base_relative, vm.peek(base_relative))),
+        Op::ST => Some(access(pc_relative, stored)),
+        Op::STR => Some(access(base_relative, stored)),
+        Op::LDI | Op::STI => {
+            let target = vm.peek(pc_relative);
+            let value = if d.op == Op::LDI { vm.peek(target) } else { stored };
+            Some(format!("{}, {}", access(pc_relative, target), access(target, value)))
+        }
+        _ => None,
+    }
+}
+
+// a control-flow change by the instruction at pc that just executed: a
+// taken branch, a jump, a call or return, or a trap, with where it went
+pub fn jump_line(vm: &VM, pc: u16, instr: u16) -> Option<String> {
+    let next = vm.read_reg(PC);
+    let moved = next != pc.wrapping_add(1);
+    let logged = match get_op(instr >> 12) {
+        Op::BR | Op::JMP | Op::JSR | Op::RTI => moved,
+        Op::TRAP => true,
+        _ => false,
+    };
+    logged.then(|| format!("x{:04X}  {:<16} -> x{:04X}", pc, disassemble(instr, pc), next))
+}
+
+// writes the binary trace, recording what each step changed by comparing
+// the registers with those after the step before
+pub struct BinaryTrace<W: Write> {
+    out: W,
+    regs: [u16; REG_COUNT],
+}
+
+impl<W: Write> BinaryTrace<W> {
+    // start a trace of vm from its current registers
+    pub fn new(mut out: W, vm: &VM) -> io::Result<BinaryTrace<W>> {
+        out.write_all(&TRACE_MAGIC)?;
+        let mut regs = [0; REG_COUNT];
+        regs.copy_from_slice(vm.registers());
+        Ok(BinaryTrace { out, regs })
+    }
+
+    // record the instruction at pc that just executed
+    pub fn record(&mut self, vm: &VM, pc: u16, instr: u16) -> io::Result<()> {
+        // the longest record, built without allocating as it's written every step
+        let mut record = [0u8; 6 + 2 * REG_COUNT];
+        let mut len = 6;
+        let mut mask = 0u16;
+        for (i, (old, &new)) in self.regs.iter_mut().zip(vm.registers()).enumerate() {
+            if *old != new && i != PC as usize {
+                mask |= 1 << i;
+                record[len..len + 2].copy_from_slice(&new.to_be_bytes());
+                len += 2;
+            }
+            *old = new;
+        }
+        record[0..2].copy_from_slice(&pc.to_be_bytes());
+        record[2..4].copy_from_slice(&instr.to_be_bytes());
+        record[4..6].copy_from_slice(&mask.to_be_bytes());
+        self.out.write_all(&record[..len])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// read as many bytes as fit in buf, fewer only at the end of the input
+fn read_full(input: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match input.read(&mut buf[n..])? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    Ok(n)
+}
+
+// one record of a binary trace, values holds the new value of each
+// register set in mask and zero for the rest
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub pc: u16,
+    pub instr: u16,
+    pub mask: u16,
+    pub values: [u16; REG_COUNT],
+}
+
+impl TraceRecord {
+    // render like --trace with the registers it changed
+    pub fn line(&self) -> String {
+        let changes: Vec<String> = (0..REG_COUNT)
+            .filter(|i| self.mask & 1 << i != 0)
+            .map(|i| format!("{} = x{:04X}", REGISTER_NAMES[i], self.values[i]))
+            .collect();
+        let line = format!("x{:04X}  x{:04X}  {}", self.pc, self.instr, disassemble(self.instr, self.pc));
+        if changes.is_empty() {
+            line
+        } else {
+            format!("{:<32} {}", line, changes.join(", "))
+        }
+    }
+}
+
+// reads the records of a binary trace one at a time
+pub struct TraceReader<R: Read> {
+    input: R,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+impl<R: Read> TraceReader<R> {
+    // check the magic at the start of input
+    pub fn new(mut input: R) -> io::Result<TraceReader<R>> {
+        let mut magic = [0u8; 4];
+        if read_full(&mut input, &mut magic)? < 4 || magic != TRACE_MAGIC {
+            return Err(invalid("not a binary trace"));
+        }
+        Ok(TraceReader { input })
+    }
+
+    // the next record, None at the end of the trace
+    pub fn next_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        let mut header = [0u8; 6];
+        match read_full(&mut self.input, &mut header)? {
+            0 => return Ok(None),
+            6 => {}
+            _ => return Err(invalid("truncated trace record")),
+        }
+        let word = |b: &[u8], i: usize| (b[i] as u16) << 8 | b[i + 1] as u16;
+        let (pc, instr, mask) = (word(&header, 0), word(&header, 2), word(&header, 4));
+        if mask & !0x2FF != 0 {
+            return Err(invalid("bad register mask in trace record"));
+        }
+        let mut bytes = vec![0u8; mask.count_ones() as usize * 2];
+        if read_full(&mut self.input, &mut bytes)? < bytes.len() {
+            return Err(invalid("truncated trace record"));
+        }
+        let mut values = [0; REG_COUNT];
+        for (i, v) in (0..REG_COUNT).filter(|i| mask & 1 << i != 0).zip(bytes.chunks(2)) {
+            values[i] = word(v, 0);
+        }
+        Ok(Some(TraceRecord { pc, instr, mask, values }))
+    }
+}
+
+// render a binary trace as text, a line per instruction like --trace with
+// the registers it changed
+pub fn decode_binary(input: &mut dyn Read, out: &mut dyn Write) -> io::Result<()> {
+    let mut reader = TraceReader::new(input)?;
+    while let Some(record) = reader.next_record()? {
+        writeln!(out, "{}", record.line())?;
+    }
+    Ok(())
+}
+
+// checks a run against a binary trace recorded earlier, step by step: the
+// same instruction at the same address changing the same registers to the
+// same values. memory isn't in the trace, a store that differs shows up only
+// once a register loaded from it does
+pub struct Replay<R: Read> {
+    reader: TraceReader<R>,
+    regs: [u16; REG_COUNT],
+    steps: u64,
+}
+
+impl<R: Read> Replay<R> {
+    // replay the trace in input against vm from its current registers
+    pub fn new(input: R, vm: &VM) -> io::Result<Replay<R>> {
+        let mut regs = [0; REG_COUNT];
+        regs.copy_from_slice(vm.registers());
+        Ok(Replay { reader: TraceReader::new(input)?, regs, steps: 0 })
+    }
+
+    // check the instruction at pc that just executed, describing the first
+    // divergence from the recording
+    pub fn check(&mut self, vm: &VM, pc: u16, instr: u16) -> Result<(), String> {
+        let mut live = TraceRecord { pc, instr, mask: 0, values: [0; REG_COUNT] };
+        for (i, (old, &new)) in self.regs.iter_mut().zip(vm.registers()).enumerate() {
+            if *old != new && i != PC as usize {
+                live.mask |= 1 << i;
+                live.values[i] = new;
+            }
+            *old = new;
+        }
+        self.steps += 1;
+        let recorded = self.reader.next_record().map_err(|e| format!("reading the trace: {}", e))?;
+        match recorded {
+            Some(recorded) if recorded == live => Ok(()),
+            Some(recorded) => Err(format!(
+                "instruction {} differs\n  recorded: {}\n  live:     {}",
+                self.steps,
+                recorded.line(),
+                live.line()
+            )),
+            None => Err(format!("the recording ends after {} instructions, the run goes on\n  live:     {}", self.steps - 1, live.line())),
+        }
+    }
+
+    // once the run is over, the instructions matched if the recording is
+    // over too
+    pub fn finish(&mut self) -> Result<u64, String> {
+        match self.reader.next_record().map_err(|e| format!("reading the trace: {}", e))? {
+            None => Ok(self.steps),
+            Some(recorded) => Err(format!(
+                "the run stopped after {} instructions, the recording goes on\n  recorded: {}",
+                self.steps,
+                recorded.line()
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::PC_START;
+
+    // ADD R0, R0, #1; ADD R1, R0, #2; HALT
+    const PROGRAM: [u16; 3] = [0x1021, 0x1222, 0xF025];
+
+    fn vm_with(words: &[u16]) -> VM {
+        let mut vm = VM::with_io(Box::new(io::empty()), Box::new(io::sink()));
+        for (i, &word) in words.iter().enumerate() {
+            vm.poke(PC_START + i as u16, word);
+        }
+        vm
+    }
+
+    // run the program to the end, recording a binary trace of it
+    fn record(words: &[u16]) -> Vec<u8> {
+        let mut vm = vm_with(words);
+        let mut trace = BinaryTrace::new(Vec::new(), &vm).unwrap();
+        while vm.running() {
+            let pc = vm.read_reg(PC);
+            let instr = vm.fetch(pc);
+            vm.step();
+            trace.record(&vm, pc, instr).unwrap();
+        }
+        trace.out
+    }
+
+    // replay the trace against the program, the first divergence or the step count
+    fn replay(trace: &[u8], words: &[u16]) -> Result<u64, String> {
+        let mut vm = vm_with(words);
+        let mut replay = Replay::new(trace, &vm).unwrap();
+        while vm.running() {
+            let pc = vm.read_reg(PC);
+            let instr = vm.fetch(pc);
+            vm.step();
+            replay.check(&vm, pc, instr)?;
+        }
+        replay.finish()
+    }
+
+    #[test]
+    fn recorded_run_replays() {
+        let trace = record(&PROGRAM);
+        // the magic, then 6 bytes per record plus 2 per changed register
+        // (R0 and COND, then R1 as COND stays positive, then R7 for the trap)
+        assert_eq!(&trace[..4], b"LC3T");
+        assert_eq!(trace.len(), 4 + 10 + 8 + 8);
+        let mut reader = TraceReader::new(&trace[..]).unwrap();
+        let first = reader.next_record().unwrap().unwrap();
+        assert_eq!((first.pc, first.instr), (PC_START, 0x1021));
+        assert_eq!(first.values[0], 1);
+        assert_eq!(replay(&trace, &PROGRAM), Ok(3));
+    }
+
+    #[test]
+    fn different_run_diverges() {
+        let trace = record(&PROGRAM);
+        // ADD R1, R0, #3 instead of #2
+        let e = replay(&trace, &[0x1021, 0x1223, 0xF025]).unwrap_err();
+        assert!(e.starts_with("instruction 2 differs\n"), "{}", e);
+        assert!(e.contains("R1 = x0003"), "{}", e);
+    }
+
+    #[test]
+    fn run_longer_or_shorter_than_the_recording_diverges() {
+        let trace = record(&PROGRAM);
+        // the recording stops after the first record
+        let e = replay(&trace[..4 + 10], &PROGRAM).unwrap_err();
+        assert!(e.starts_with("the recording ends after 1 instructions, the run goes on"), "{}", e);
+        // a run that ends after its first instruction
+        let mut vm = vm_with(&PROGRAM);
+        let mut replay = Replay::new(&trace[..], &vm).unwrap();
+        vm.step();
+        replay.check(&vm, PC_START, PROGRAM[0]).unwrap();
+        let e = replay.finish().unwrap_err();
+        assert!(e.starts_with("the run stopped after 1 instructions, the recording goes on"), "{}", e);
+    }
+}
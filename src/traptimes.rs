@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+use std::time::Duration;
+use crate::disasm::trap_name;
+
+// how often a trap vector was called and the wall-clock time spent in it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrapTime {
+    pub calls: u64,
+    pub time: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrapTimes {
+    times: BTreeMap<u8, TrapTime>,
+}
+
+impl TrapTimes {
+    pub fn new() -> TrapTimes {
+        TrapTimes::default()
+    }
+
+    pub fn record(&mut self, vector: u8, time: Duration) {
+        let t = self.times.entry(vector).or_default();
+        t.calls += 1;
+        t.time += time;
+    }
+
+    pub fn get(&self, vector: u8) -> Option<TrapTime> {
+        self.times.get(&vector).copied()
+    }
+
+    // every vector called, in vector order
+    pub fn iter(&self) -> impl Iterator<Item = (u8, TrapTime)> + '_ {
+        self.times.iter().map(|(&v, &t)| (v, t))
+    }
+
+    // print a line per vector called, the most time first
+    pub fn report(&self, out: &mut dyn Write) -> io::Result<()> {
+        let mut traps: Vec<_> = self.iter().collect();
+        traps.sort_by_key(|(_, t)| std::cmp::Reverse(t.time));
+        let total: Duration = traps.iter().map(|(_, t)| t.time).sum();
+        writeln!(out, "{:.3}ms in traps", total.as_secs_f64() * 1000.0)?;
+        for (vector, t) in traps {
+            let name = trap_name(vector as u16).unwrap_or("");
+            let each = t.time.as_secs_f64() * 1e6 / t.calls as f64;
+            writeln!(
+                out,
+                "  x{:02X} {:<6} {:>8} calls {:>10.3}ms {:>10.1}us each",
+                vector,
+                name,
+                t.calls,
+                t.time.as_secs_f64() * 1000.0,
+                each
+            )?;
+        }
+        Ok(())
+    }
+}
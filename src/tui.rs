@@ -0,0 +1,225 @@
+// a full-screen debugger: the registers, the disassembly around PC with its
+// line highlighted, a memory view the arrow keys scroll, the output and a
+// command line taking the line debugger's commands. it's drawn with ANSI
+// escapes on the same fixed 80x24 grid as --screen, so the crate needs no
+// terminal library
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use termios::*;
+use crate::debugger::Debugger;
+use crate::disasm::disassemble;
+use crate::runner::Capture;
+use crate::screen::{COLS, ROWS};
+use crate::vm::RegisterType::PC;
+use crate::vm::REGISTER_NAMES;
+
+// a row per register beside the disassembly
+const CODE_ROWS: usize = 10;
+// instructions shown above PC
+const CODE_BEFORE: u16 = 3;
+const MEMORY_ROWS: usize = 5;
+const WORDS_PER_ROW: u16 = 8;
+// what's left after the title, the two panel headers and the command line
+const LOG_ROWS: usize = ROWS - CODE_ROWS - MEMORY_ROWS - 4;
+// width of the register column
+const REGS_WIDTH: usize = 22;
+// output kept for the log panel, older text is dropped
+const LOG_LIMIT: usize = 64 * 1024;
+
+// the terminal in raw mode on the alternate screen, restored when dropped
+// however the debugger exits
+struct Terminal {
+    tty: File,
+    saved: Termios,
+}
+
+impl Terminal {
+    fn enter(tty: &File) -> io::Result<Terminal> {
+        let mut tty = tty.try_clone()?;
+        let saved = Termios::from_fd(tty.as_raw_fd())?;
+        let mut raw = saved;
+        raw.c_lflag &= !(ICANON | ECHO);
+        tcsetattr(tty.as_raw_fd(), TCSANOW, &raw)?;
+        tty.write_all(b"\x1b[?1049h\x1b[2J")?;
+        Ok(Terminal { tty, saved })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = tcsetattr(self.tty.as_raw_fd(), TCSANOW, &self.saved);
+        let _ = self.tty.write_all(b"\x1b[?1049l");
+    }
+}
+
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    // the input ended or Ctrl-D
+    End,
+    Other,
+}
+
+fn read_byte(input: &mut dyn Read) -> io::Result<Option<u8>> {
+    let mut byte = [0u8; 1];
+    Ok((input.read(&mut byte)? == 1).then_some(byte[0]))
+}
+
+// the next key, arrows and page keys arrive as escape sequences
+fn read_key(input: &mut dyn Read) -> io::Result<Key> {
+    let Some(byte) = read_byte(input)? else {
+        return Ok(Key::End);
+    };
+    Ok(match byte {
+        b'\r' | b'\n' => Key::Enter,
+        0x7f | 0x08 => Key::Backspace,
+        0x04 => Key::End,
+        0x1b => match (read_byte(input)?, read_byte(input)?) {
+            (Some(b'['), Some(b'A')) => Key::Up,
+            (Some(b'['), Some(b'B')) => Key::Down,
+            (Some(b'['), Some(b'5')) if read_byte(input)? == Some(b'~') => Key::PageUp,
+            (Some(b'['), Some(b'6')) if read_byte(input)? == Some(b'~') => Key::PageDown,
+            _ => Key::Other,
+        },
+        b' '..=b'~' => Key::Char(byte as char),
+        _ => Key::Other,
+    })
+}
+
+// cut to the grid's width, the text is ASCII
+fn fit(text: &str, width: usize) -> String {
+    format!("{:<width$.width$}", text, width = width)
+}
+
+struct Tui {
+    // address at the top of the memory view
+    memory: u16,
+    command: String,
+    // command output and program output, newest at the end
+    log: String,
+}
+
+impl Tui {
+    // add text to the log, dropping control characters that would move the
+    // cursor around the screen
+    fn log(&mut self, text: &[u8]) {
+        self.log.extend(text.iter().filter(|&&b| b == b'\n' || (b' '..=b'~').contains(&b)).map(|&b| b as char));
+        if self.log.len() > LOG_LIMIT {
+            let cut = self.log.len() - LOG_LIMIT / 2;
+            self.log.drain(..cut);
+        }
+    }
+
+    // start a new log line unless the log is at one
+    fn new_line(&mut self) {
+        if !self.log.is_empty() && !self.log.ends_with('\n') {
+            self.log.push('\n');
+        }
+    }
+
+    fn scroll(&mut self, rows: i32) {
+        self.memory = self.memory.wrapping_add((rows * WORDS_PER_ROW as i32) as u16);
+    }
+
+    fn draw(&self, debugger: &Debugger, out: &mut dyn Write) -> io::Result<()> {
+        let vm = &debugger.vm;
+        let pc = vm.read_reg(PC);
+        let mut lines = Vec::with_capacity(ROWS);
+        let state = match vm.halt_reason() {
+            Some(reason) => format!("stopped: {}", reason),
+            None => "running".to_string(),
+        };
+        lines.push(format!("\x1b[7m{}\x1b[0m", fit(&format!(" lc3  PC x{:04X}  {}", pc, state), COLS)));
+
+        let regs = vm.registers();
+        for i in 0..CODE_ROWS {
+            let reg = match (REGISTER_NAMES.get(i), regs.get(i)) {
+                (Some(name), Some(&v)) => format!("{:<8} x{:04X} {:>6}", name, v, v as i16),
+                _ => String::new(),
+            };
+            let a = pc.wrapping_sub(CODE_BEFORE).wrapping_add(i as u16);
+            let marker = if debugger.breakpoints().contains(&a) { '*' } else { ' ' };
+            let word = vm.peek(a);
+            let code = fit(&format!("{} x{:04X}  x{:04X}  {}", marker, a, word, disassemble(word, a)), COLS - REGS_WIDTH - 2);
+            if a == pc {
+                lines.push(format!("{}| \x1b[7m{}\x1b[0m", fit(&reg, REGS_WIDTH), code));
+            } else {
+                lines.push(format!("{}| {}", fit(&reg, REGS_WIDTH), code));
+            }
+        }
+
+        lines.push(format!("{:-<width$}", format!("-- memory x{:04X}, arrows and PgUp/PgDn scroll ", self.memory), width = COLS));
+        for row in 0..MEMORY_ROWS {
+            let start = self.memory.wrapping_add(row as u16 * WORDS_PER_ROW);
+            let words: Vec<u16> = (0..WORDS_PER_ROW).map(|i| vm.peek(start.wrapping_add(i))).collect();
+            let hex: Vec<String> = words.iter().map(|w| format!("{:04X}", w)).collect();
+            let text: String = words.iter().map(|&w| if (0x20..0x7f).contains(&w) { w as u8 as char } else { '.' }).collect();
+            lines.push(fit(&format!("x{:04X}: {}  {}", start, hex.join(" "), text), COLS));
+        }
+
+        lines.push(format!("{:-<width$}", "-- output, help lists the commands ", width = COLS));
+        let log: Vec<&str> = self.log.lines().collect();
+        for i in 0..LOG_ROWS {
+            let line = (log.len() + i).checked_sub(LOG_ROWS).and_then(|n| log.get(n)).unwrap_or(&"");
+            lines.push(fit(line, COLS));
+        }
+
+        let prompt = format!("(lc3) {}", self.command);
+        lines.push(fit(&prompt, COLS - 1));
+        // home, then draw over the last frame to avoid flicker
+        write!(out, "\x1b[H{}", lines.join("\r\n"))?;
+        write!(out, "\x1b[{};{}H", ROWS, prompt.len().min(COLS - 1) + 1)?;
+        out.flush()
+    }
+}
+
+// run the debugger full screen on the controlling terminal until q or Ctrl-D.
+// the program's keyboard stays as it was, its output goes to the log panel
+pub fn run(debugger: &mut Debugger) -> io::Result<()> {
+    let mut tty = File::options().read(true).write(true).open("/dev/tty")?;
+    let _terminal = Terminal::enter(&tty)?;
+    let capture = Capture::default();
+    debugger.vm.set_output(Box::new(capture.clone()));
+    let memory = debugger.vm.read_reg(PC) & !(WORDS_PER_ROW - 1);
+    let mut tui = Tui { memory, command: String::new(), log: String::new() };
+    loop {
+        let output = capture.take();
+        tui.log(&output);
+        tui.draw(debugger, &mut tty)?;
+        match read_key(&mut tty)? {
+            Key::Char(c) => tui.command.push(c),
+            Key::Backspace => {
+                tui.command.pop();
+            }
+            Key::Up => tui.scroll(-1),
+            Key::Down => tui.scroll(1),
+            Key::PageUp => tui.scroll(-(MEMORY_ROWS as i32)),
+            Key::PageDown => tui.scroll(MEMORY_ROWS as i32),
+            Key::Enter => {
+                let line = std::mem::take(&mut tui.command);
+                tui.new_line();
+                tui.log(format!("(lc3) {}\n", line).as_bytes());
+                let mut out = Vec::new();
+                let more = debugger.command(&line, &mut out)?;
+                // the program's output while it ran comes before the debugger's report
+                let output = capture.take();
+                tui.log(&output);
+                tui.new_line();
+                tui.log(&out);
+                if !more {
+                    return Ok(());
+                }
+            }
+            Key::End => return Ok(()),
+            Key::Other => {}
+        }
+    }
+}
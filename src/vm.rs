@@ -0,0 +1,2244 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::{fmt, io};
+use std::io::{stdin, stdout, Read, Write};
+use std::time::Instant;
+use crate::branches::BranchStats;
+use crate::consts::{
+    OP_ADD, OP_AND, OP_BR, OP_JMP, OP_JSR, OP_LD, OP_LDI, OP_LDR, OP_LEA, OP_NOT, OP_RES, OP_RTI, OP_ST, OP_STI, OP_STR,
+    OP_TRAP, INT_KEYBOARD, INT_TABLE, TRAP_GETC, TRAP_HALT, TRAP_IN, TRAP_OUT, TRAP_PUTS, TRAP_PUTSP,
+};
+use crate::coverage::Coverage;
+use crate::disasm::trap_name;
+use crate::image::{decode_base64, read_sections, sections_from_bytes, Image};
+use crate::loops::LoopDetector;
+use crate::screen::Screen;
+use crate::traptimes::TrapTimes;
+use crate::vm::RegisterType::{COND, PC, R0, R1, R6, R7};
+
+pub use crate::consts::{TRAP_BUDGET, TRAP_DEBUG};
+
+pub const MEMORY_MAX: usize = 1 << 16;
+pub const REG_COUNT: usize = 10;
+pub const PC_START: u16 = 0x3000;
+// a fill for memory that makes reads of never-written words stand out
+pub const POISON: u16 = 0xDEAD;
+// arguments from the host: the count at ARGC and the words from ARGV on
+pub const ARGC: u16 = 0x2FF0;
+pub const ARGV: u16 = 0x2FF1;
+pub const MAX_ARGS: usize = 15;
+pub const MR_KBSR: u16 = 0xFE00;
+pub const MR_KBDR: u16 = 0xFE02;  /* keyboard data */
+pub const MR_DSR: u16 = 0xFE04;  /* display status */
+pub const MR_DDR: u16 = 0xFE06;  /* display data */
+pub const MR_MCR: u16 = 0xFFFE;  /* machine control */
+// the keyboard's interrupt enable bit in KBSR
+pub const KBSR_IE: u16 = 1 << 14;
+// the priority level the keyboard interrupts at
+pub const KEYBOARD_PRIORITY: u16 = 4;
+// where the supervisor stack starts, growing down below the user program
+pub const SUPERVISOR_STACK: u16 = 0x3000;
+
+// register names by index, R6 and R7 carry their conventional roles
+pub const REGISTER_NAMES: [&str; REG_COUNT] =
+    ["R0", "R1", "R2", "R3", "R4", "R5", "R6(SP)", "R7(RET)", "PC", "COND"];
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterType {
+    R0,
+    R1,
+    R2,
+    R3,
+    R4,
+    R5,
+    R6,
+    R7,
+    PC,
+    COND,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Op {
+    BR,
+    ADD,
+    LD,
+    ST,
+    JSR,
+    AND,
+    LDR,
+    STR,
+    RTI,
+    NOT,
+    LDI,
+    STI,
+    JMP,
+    RES,
+    LEA,
+    TRAP,
+    Unknown,
+}
+
+// opcode mnemonics by op code
+pub const OPCODE_NAMES: [&str; 16] = [
+    "BR", "ADD", "LD", "ST", "JSR", "AND", "LDR", "STR", "RTI", "NOT", "LDI", "STI", "JMP", "RES", "LEA", "TRAP",
+];
+
+// an estimate of what instructions cost: a cycle count per opcode, plus
+// a count per data load or store the instruction makes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CycleCosts {
+    pub opcodes: [u64; 16],
+    pub memory_access: u64,
+}
+
+impl Default for CycleCosts {
+    // every instruction costs 1 and memory is free, so cycles count instructions
+    fn default() -> Self {
+        CycleCosts { opcodes: [1; 16], memory_access: 0 }
+    }
+}
+
+// get op enum by op code
+pub fn get_op(op_code: u16) -> Op {
+    match op_code {
+        OP_BR => Op::BR,
+        OP_ADD => Op::ADD,
+        OP_LD => Op::LD,
+        OP_ST => Op::ST,
+        OP_JSR => Op::JSR,
+        OP_AND => Op::AND,
+        OP_LDR => Op::LDR,
+        OP_STR => Op::STR,
+        OP_RTI => Op::RTI,
+        OP_NOT => Op::NOT,
+        OP_LDI => Op::LDI,
+        OP_STI => Op::STI,
+        OP_JMP => Op::JMP,
+        OP_RES => Op::RES,
+        OP_LEA => Op::LEA,
+        OP_TRAP => Op::TRAP,
+        _ => Op::Unknown,
+    }
+}
+
+// an instruction split into its fields, every field decoded whether or not
+// the opcode uses it. the offsets and the immediate are sign-extended as the
+// executor extends them, the trap vector is zero-extended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedInstr {
+    pub op: Op,
+    // bits 11-9: the destination register, the source of ST, STR and STI
+    pub dr: u16,
+    // bits 11-9 again, the n, z and p flags of BR
+    pub nzp: u16,
+    // bits 8-6: the first source register, the base register of LDR, STR, JMP and JSRR
+    pub sr1: u16,
+    pub base_r: u16,
+    // bits 2-0: the second source register of ADD and AND
+    pub sr2: u16,
+    // bit 5 of ADD and AND: imm5 is the second operand instead of SR2
+    pub imm_mode: bool,
+    pub imm5: i16,
+    pub offset6: i16,
+    pub pc_offset9: i16,
+    // bit 11 of JSR: PCoffset11 is the target instead of BaseR
+    pub jsr_offset: bool,
+    pub pc_offset11: i16,
+    pub trap_vect: u16,
+}
+
+pub fn decode(instr: u16) -> DecodedInstr {
+    let r0 = (instr >> 9) & 0x7;
+    let r1 = (instr >> 6) & 0x7;
+    DecodedInstr {
+        op: get_op(instr >> 12),
+        dr: r0,
+        nzp: r0,
+        sr1: r1,
+        base_r: r1,
+        sr2: instr & 0x7,
+        imm_mode: (instr >> 5) & 0x1 == 1,
+        imm5: sign_extend(instr & 0x1f, 5) as i16,
+        offset6: sign_extend(instr & 0x3f, 6) as i16,
+        pc_offset9: sign_extend(instr & 0x1ff, 9) as i16,
+        jsr_offset: (instr >> 11) & 0x1 == 1,
+        pc_offset11: sign_extend(instr & 0x7ff, 11) as i16,
+        trap_vect: instr & 0xff,
+    }
+}
+
+// the condition codes, over COND's bit layout: N is bit 2, Z bit 1 and P
+// bit 0. an instruction that sets them sets exactly one. BR's nzp field uses
+// the same layout
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags {
+    pub n: bool,
+    pub z: bool,
+    pub p: bool,
+}
+
+impl Flags {
+    pub const N: u16 = 1 << 2;
+    pub const Z: u16 = 1 << 1;
+    pub const P: u16 = 1 << 0;
+
+    // from COND or an nzp field, bits above the three are ignored
+    pub fn from_bits(bits: u16) -> Flags {
+        Flags { n: bits & Flags::N != 0, z: bits & Flags::Z != 0, p: bits & Flags::P != 0 }
+    }
+
+    pub fn bits(self) -> u16 {
+        (if self.n { Flags::N } else { 0 }) | (if self.z { Flags::Z } else { 0 }) | (if self.p { Flags::P } else { 0 })
+    }
+
+    // the flag a result sets: negative, zero or positive as a signed word
+    pub fn of(val: u16) -> Flags {
+        Flags { n: (val as i16) < 0, z: val == 0, p: (val as i16) > 0 }
+    }
+}
+
+// a write to a watched register
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterWrite {
+    pub index: u16,
+    pub old: u16,
+    pub new: u16,
+}
+
+// why the VM stopped running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HaltReason {
+    // the program executed TRAP_HALT
+    Halt,
+    // the program asked for a key but the input stream is exhausted
+    InputExhausted,
+    // the program wrote to this address in a protected range, the write was dropped
+    WriteProtected(u16),
+    // strict opcodes are on and the word at address has the reserved opcode
+    IllegalOpcode { address: u16, word: u16 },
+    // the program tried to write more than this many bytes
+    OutputLimit(u64),
+    // the program used up its budget of this many cycles
+    CycleLimit(u64),
+    // the store at pc wrote into the null guard's range, the write was dropped
+    NullWrite { address: u16, pc: u16 },
+    // the whole machine state at pc recurred after period instructions, so
+    // the program would repeat them forever
+    InfiniteLoop { pc: u16, period: u64 },
+}
+
+impl HaltReason {
+    // the variant's name without its details, e.g. for a machine-readable report
+    pub fn name(&self) -> &'static str {
+        match self {
+            HaltReason::Halt => "Halt",
+            HaltReason::InputExhausted => "InputExhausted",
+            HaltReason::WriteProtected(_) => "WriteProtected",
+            HaltReason::IllegalOpcode { .. } => "IllegalOpcode",
+            HaltReason::OutputLimit(_) => "OutputLimit",
+            HaltReason::CycleLimit(_) => "CycleLimit",
+            HaltReason::NullWrite { .. } => "NullWrite",
+            HaltReason::InfiniteLoop { .. } => "InfiniteLoop",
+        }
+    }
+}
+
+impl fmt::Display for HaltReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HaltReason::Halt => write!(f, "Halt"),
+            HaltReason::InputExhausted => write!(f, "InputExhausted"),
+            HaltReason::WriteProtected(a) => write!(f, "WriteProtected at x{:04X}", a),
+            HaltReason::IllegalOpcode { address, word } => write!(f, "IllegalOpcode x{:04X} at x{:04X}", word, address),
+            HaltReason::OutputLimit(n) => write!(f, "OutputLimit of {} bytes", n),
+            HaltReason::CycleLimit(n) => write!(f, "CycleLimit of {} cycles", n),
+            HaltReason::NullWrite { address, pc } => write!(f, "NullWrite to x{:04X} at x{:04X}", address, pc),
+            HaltReason::InfiniteLoop { pc, period } => write!(f, "InfiniteLoop at x{:04X} every {} instructions", pc, period),
+        }
+    }
+}
+
+// what a step left the VM to do next. only the caller decides what to do
+// about a stop, the VM never exits the process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    // the VM is still running
+    Continue,
+    // the program halted normally, with HALT or by clearing the clock enable bit
+    Halt,
+    // the step entered an interrupt handler instead of executing an instruction,
+    // only produced with interrupts on
+    Interrupt,
+    // the VM stopped on anything other than a normal halt
+    Error(HaltReason),
+}
+
+// called before each instruction with the PC it's fetched from
+pub type PreStepHook = Box<dyn FnMut(&VM, u16) + Send>;
+// called after each instruction with its PC and instruction word
+pub type PostStepHook = Box<dyn FnMut(&VM, u16, u16) + Send>;
+
+// the processor status the interrupt machinery keeps next to COND. the PSR
+// is the privilege in bit 15 (1 for user mode), the priority level 0-7 in
+// bits 10-8 and COND in bits 2-0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct Status {
+    user: bool,
+    priority: u16,
+    // R6 of the mode not running, swapped in when the mode changes
+    saved_ssp: u16,
+    saved_usp: u16,
+    // a key was read for an interrupt and KBDR hasn't been read since
+    key_ready: bool,
+    // the vector requested at each priority level and not yet serviced
+    requests: [Option<u8>; 8],
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Status { user: true, priority: 0, saved_ssp: SUPERVISOR_STACK, saved_usp: 0, key_ready: false, requests: [None; 8] }
+    }
+}
+
+// what one step changed, enough to undo it: the registers and state before
+// it and the old value of every memory word it wrote, in write order
+struct Delta {
+    regs: Vec<u16>,
+    status: Status,
+    instructions: u64,
+    halted: Option<HaltReason>,
+    overflow: Option<bool>,
+    cycles: u64,
+    memory: Vec<(u16, u16)>,
+}
+
+// the most recent steps, oldest first
+struct History {
+    depth: usize,
+    steps: VecDeque<Delta>,
+    // the delta of the step being executed
+    current: Option<Delta>,
+}
+
+// a Rust implementation of a trap vector, see VM::register_trap
+pub type TrapHandler = Box<dyn FnMut(&mut VM) + Send>;
+
+pub struct VM {
+    regs: Vec<u16>,
+    memory: Vec<u16>,
+    input: Box<dyn Read + Send>,
+    output: Box<dyn Write + Send>,
+    // bytes written to output and the most allowed
+    output_written: u64,
+    output_limit: Option<u64>,
+    halted: Option<HaltReason>,
+    // instructions executed so far
+    instructions: u64,
+    // dispatch TRAP through the trap vector table instead of the built-in routines
+    trap_table: bool,
+    debug_trap: bool,
+    echo: bool,
+    // the inclusive range a program store into is taken for a null pointer
+    // bug, and whether it stops the VM or only warns
+    null_guard: Option<(u16, u16)>,
+    null_guard_abort: bool,
+    // bytes read from the input replaced before the program sees them
+    key_map: BTreeMap<u8, u8>,
+    // mask the characters OUT, PUTS and PUTSP print to 7 bits
+    strip_high_bit: bool,
+    strict_opcodes: bool,
+    // inclusive address ranges the program may not write
+    protected: Vec<(u16, u16)>,
+    // a bit per register index
+    register_watches: u16,
+    register_write: Option<RegisterWrite>,
+    pre_step_hook: Option<PreStepHook>,
+    post_step_hook: Option<PostStepHook>,
+    coverage: Option<Coverage>,
+    branches: Option<BranchStats>,
+    trap_times: Option<TrapTimes>,
+    traps: BTreeMap<u8, TrapHandler>,
+    // (origin, word count) of every image section loaded, in load order
+    loaded: Vec<(u16, usize)>,
+    // a bit per address, set once anything wrote the word: loading, poke or a store
+    written: Vec<u64>,
+    history: Option<History>,
+    // signed overflow of the last ADD, None unless tracking is on
+    overflow: Option<bool>,
+    // estimated cycles so far, counted only when costs are set
+    cycle_costs: Option<CycleCosts>,
+    cycles: u64,
+    // the inclusive range an R6 stack must stay in, None when not checked
+    stack: Option<(u16, u16)>,
+    cycle_limit: Option<u64>,
+    budget_trap: bool,
+    // warn when the program writes the read-only keyboard registers
+    strict_mmio: bool,
+    trace_traps: bool,
+    screen: Option<Screen>,
+    loops: Option<LoopDetector>,
+    // service interrupts, off by default as programs usually poll the keyboard
+    interrupts: bool,
+    status: Status,
+}
+
+// the VM can be moved to a worker thread, keep it that way
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<VM>();
+};
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl VM {
+    // create a VM reading the keyboard from stdin and writing to stdout
+    pub fn new() -> VM {
+        Self::with_io(Box::new(stdin()), Box::new(stdout()))
+    }
+
+    // replace the console output stream, e.g. to collect what the program prints
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        self.output = output;
+    }
+
+    // create a VM with the given keyboard input and console output streams
+    pub fn with_io(input: Box<dyn Read + Send>, output: Box<dyn Write + Send>) -> VM {
+        let mut vm = VM {
+            regs: vec![0u16; REG_COUNT],
+            memory: vec![0u16; MEMORY_MAX],
+            input,
+            output,
+            output_written: 0,
+            output_limit: None,
+            halted: None,
+            instructions: 0,
+            trap_table: false,
+            debug_trap: false,
+            echo: false,
+            null_guard: None,
+            null_guard_abort: false,
+            key_map: BTreeMap::new(),
+            strip_high_bit: false,
+            strict_opcodes: false,
+            protected: Vec::new(),
+            register_watches: 0,
+            register_write: None,
+            pre_step_hook: None,
+            post_step_hook: None,
+            coverage: None,
+            branches: None,
+            trap_times: None,
+            traps: BTreeMap::new(),
+            loaded: Vec::new(),
+            written: vec![0; MEMORY_MAX / 64],
+            history: None,
+            overflow: None,
+            cycle_costs: None,
+            cycles: 0,
+            stack: None,
+            cycle_limit: None,
+            budget_trap: false,
+            strict_mmio: false,
+            trace_traps: false,
+            screen: None,
+            loops: None,
+            interrupts: false,
+            status: Status::default(),
+        };
+        vm.write_reg(PC, PC_START);
+        // every register starts at zero, so the flags say zero as on hardware,
+        // a BRz before any arithmetic is taken
+        vm.update_flags_by_val(0);
+        vm
+    }
+
+    // get the register index by register type enum
+    fn get_index(t: RegisterType) -> usize {
+        match t {
+            RegisterType::R0 => 0,
+            RegisterType::R1 => 1,
+            RegisterType::R2 => 2,
+            RegisterType::R3 => 3,
+            RegisterType::R4 => 4,
+            RegisterType::R5 => 5,
+            RegisterType::R6 => 6,
+            RegisterType::R7 => 7,
+            RegisterType::PC => 8,
+            RegisterType::COND => 9,
+        }
+    }
+
+    // read reg by register type enum
+    pub fn read_reg(&self, t: RegisterType) -> u16 {
+        self.regs[Self::get_index(t)]
+    }
+
+    // write reg by register type enum
+    pub fn write_reg(&mut self, t: RegisterType, v: u16) {
+        self.set_reg(Self::get_index(t), v);
+    }
+
+    // every register write goes through here so watched registers are seen,
+    // one mask test when nothing is watched
+    fn set_reg(&mut self, i: usize, v: u16) {
+        if self.register_watches & (1 << i) != 0 && self.register_write.is_none() {
+            self.register_write = Some(RegisterWrite { index: i as u16, old: self.regs[i], new: v });
+        }
+        self.regs[i] = v;
+    }
+
+    // stop at the next write of register i, or stop watching it
+    pub fn watch_register(&mut self, i: u16, on: bool) {
+        if on {
+            self.register_watches |= 1 << i;
+        } else {
+            self.register_watches &= !(1 << i);
+        }
+    }
+
+    // the watched register indexes
+    pub fn register_watches(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..REG_COUNT as u16).filter(|i| self.register_watches & (1 << i) != 0)
+    }
+
+    // the first write of a watched register since the last call
+    pub fn take_register_write(&mut self) -> Option<RegisterWrite> {
+        self.register_write.take()
+    }
+
+    // all registers in index order, R0-R7 then PC and COND
+    pub fn registers(&self) -> &[u16] {
+        &self.regs
+    }
+
+    // COND as flags
+    pub fn cond_flags(&self) -> Flags {
+        Flags::from_bits(self.read_reg(COND))
+    }
+
+    // write COND from flags. nothing checks that exactly one is set, a branch
+    // simply tests whichever are
+    pub fn set_cond_flags(&mut self, flags: Flags) {
+        self.write_reg(COND, flags.bits());
+    }
+
+    // read reg by register index
+    pub fn read_reg_by_index(&self, i: u16) -> u16 {
+        self.regs[i as usize]
+    }
+
+    // write reg by register index
+    pub fn write_reg_by_index(&mut self, i: u16, v: u16) {
+        self.set_reg(i as usize, v);
+    }
+
+    pub fn read_pc(&mut self) -> u16 {
+        self.regs[Self::get_index(PC)]
+    }
+
+    // program counter increment 1, wrapping from 0xFFFF to 0x0000
+    fn add_pc(&mut self) {
+        let pc = &mut self.regs[Self::get_index(PC)];
+        *pc = pc.wrapping_add(1);
+    }
+
+    // an instruction fetch, it never triggers device registers or changes anything
+    pub fn fetch(&self, address: u16) -> u16 {
+        self.memory[address as usize]
+    }
+
+    // a data load by LD, LDR, LDI and the traps, reading a device register
+    // polls the device
+    pub fn load(&mut self, address: u16) -> u16 {
+        self.charge_memory_access();
+        // polling keeps the interrupt enable bit as the program set it
+        let ie = self.memory[MR_KBSR as usize] & KBSR_IE;
+        if address == MR_KBSR && self.status.key_ready {
+            // a key read for an interrupt is waiting in KBDR
+        } else if address == MR_KBSR {
+            if check_key() {
+                self.store(MR_KBSR, 1 << 15 | ie);
+                let c = self.read_char();
+                self.store(MR_KBDR, c as u16);
+            } else {
+                self.store(MR_KBSR, ie);
+            }
+        } else if address == MR_KBDR && self.status.key_ready {
+            // reading the key clears the ready bit, ending the interrupt request
+            self.status.key_ready = false;
+            self.store(MR_KBSR, ie);
+        } else if address == MR_DSR {
+            // the display is always ready
+            self.store(MR_DSR, 1 << 15);
+        }
+        self.memory[address as usize]
+    }
+
+    // write val to memory
+    pub fn write_memory(&mut self, address: u16, val: u16) {
+        self.charge_memory_access();
+        if let Some((start, end)) = self.null_guard.filter(|&(s, e)| (s..=e).contains(&address)) {
+            let pc = self.read_reg(PC).wrapping_sub(1);
+            if self.null_guard_abort {
+                self.halted = Some(HaltReason::NullWrite { address, pc });
+                return;
+            }
+            eprintln!("warning: write of x{:04X} to x{:04X} at x{:04X}, inside the null guard x{:04X}-x{:04X}", val, address, pc, start, end);
+        }
+        // only the interrupt enable bit of KBSR is writable on real hardware
+        let read_only = address == MR_KBDR || (address == MR_KBSR && val & !KBSR_IE != 0);
+        if self.strict_mmio && read_only {
+            // the next keyboard poll overwrites the word, hiding the bug
+            let pc = self.read_reg(PC).wrapping_sub(1);
+            eprintln!("warning: write of x{:04X} to the read-only keyboard register x{:04X} at x{:04X}", val, address, pc);
+        }
+        self.store(address, val);
+    }
+
+    // a write to memory that isn't an access of the program's, e.g. a device
+    // updating its status register
+    fn store(&mut self, address: u16, val: u16) {
+        if self.is_protected(address) {
+            self.halted = Some(HaltReason::WriteProtected(address));
+            return;
+        }
+        if let Some(delta) = self.history.as_mut().and_then(|h| h.current.as_mut()) {
+            delta.memory.push((address, self.memory[address as usize]));
+        }
+        if address == MR_DDR {
+            self.put_char(val as u8);
+            self.flush();
+        } else if address == MR_MCR && (val >> 15) == 0 {
+            // clearing the clock enable bit stops the machine
+            self.halted = Some(HaltReason::Halt);
+        } else if let Some(screen) = self.screen.as_mut().filter(|_| Screen::handles(address)) {
+            for c in screen.write(address, val) {
+                self.put_char(c);
+            }
+            self.flush();
+        }
+        self.set_word(address, val);
+    }
+
+    // every write to memory comes through here but restoring history and fills
+    fn set_word(&mut self, address: u16, val: u16) {
+        if let Some(loops) = &mut self.loops {
+            loops.write(address, self.memory[address as usize], val);
+        }
+        self.memory[address as usize] = val;
+        self.written[address as usize / 64] |= 1 << (address % 64);
+    }
+
+    // has anything ever written the word at address, the loader, poke or the
+    // program. a word only filled by fill_memory doesn't count
+    pub fn was_written(&self, address: u16) -> bool {
+        self.written[address as usize / 64] & 1 << (address % 64) != 0
+    }
+
+    // make start..=end read-only to the program, a store into it stops the
+    // VM instead. the device registers stay writable, loading and poke aren't affected
+    pub fn protect(&mut self, start: u16, end: u16) {
+        self.protected.push((start, end));
+    }
+
+    // place a host file's bytes at origin as big-endian words and make them
+    // read-only, e.g. a large lookup table kept out of the program's image.
+    // memory is only 64K words, so the file is read in whole rather than mapped
+    pub fn map_rom(&mut self, origin: u16, bytes: &[u8]) -> io::Result<()> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        if bytes.is_empty() || bytes.len() % 2 == 1 {
+            return Err(invalid(format!("a ROM needs a whole number of words, not {} bytes", bytes.len())));
+        }
+        let image = Image { origin, words: bytes.chunks_exact(2).map(|w| (w[0] as u16) << 8 | w[1] as u16).collect() };
+        image.check_fits()?;
+        let last = (image.end() - 1) as u16;
+        if last >= MR_KBSR {
+            return Err(invalid(format!("a ROM at x{:04X}-x{:04X} would cover the device registers", origin, last)));
+        }
+        for (i, word) in image.words.iter().enumerate() {
+            self.poke(origin + i as u16, *word);
+        }
+        self.protect(origin, last);
+        Ok(())
+    }
+
+    fn is_protected(&self, address: u16) -> bool {
+        address < MR_KBSR && self.protected.iter().any(|&(start, end)| (start..=end).contains(&address))
+    }
+
+    // read memory without triggering device registers
+    pub fn peek(&self, address: u16) -> u16 {
+        self.memory[address as usize]
+    }
+
+    // write memory without triggering device registers
+    pub fn poke(&mut self, address: u16, val: u16) {
+        self.set_word(address, val);
+    }
+
+    // copy len words of memory from start as an image, without triggering
+    // device registers. the copy stops at the end of memory
+    pub fn export_region(&self, start: u16, len: usize) -> Image {
+        let end = (start as usize + len).min(MEMORY_MAX);
+        Image { origin: start, words: self.memory[start as usize..end].to_vec() }
+    }
+
+    // set every memory word to val, poison it before loading so a program
+    // relying on zero-filled memory shows up in traces
+    pub fn fill_memory(&mut self, val: u16) {
+        self.memory.fill(val);
+        if let Some(loops) = &mut self.loops {
+            loops.rehash(&self.memory);
+        }
+    }
+
+    // update cond register by given val
+    fn update_flags_by_val(&mut self, val: u16) {
+        self.set_cond_flags(Flags::of(val));
+    }
+
+    // update cond register by Register Index
+    fn update_flags_by_index(&mut self, i: u16) {
+        let val = self.read_reg_by_index(i);
+        self.update_flags_by_val(val);
+    }
+
+    // update cond register by RegisterType
+    fn update_flags(&mut self, t: RegisterType) {
+        let val = self.read_reg(t);
+        self.update_flags_by_val(val);
+    }
+
+    // read a char from the keyboard input stream,
+    // the VM halts when the stream is exhausted
+    fn read_char(&mut self) -> u8 {
+        self.try_read_char().unwrap_or_else(|| {
+            self.halted = Some(HaltReason::InputExhausted);
+            0
+        })
+    }
+
+    // read a char from the keyboard input stream, None once it's exhausted
+    fn try_read_char(&mut self) -> Option<u8> {
+        let mut buf = [0u8];
+        self.input.read_exact(&mut buf).ok()?;
+        // what the program does next can depend on the key
+        if let Some(loops) = &mut self.loops {
+            loops.forget();
+        }
+        let c = self.key_map.get(&buf[0]).copied().unwrap_or(buf[0]);
+        if self.echo {
+            self.put_char(c);
+            self.flush();
+        }
+        Some(c)
+    }
+
+    // write a single byte to the console output, every trap and the display
+    // register come through here. past the output limit the byte is dropped
+    // and the VM stops
+    fn put_char(&mut self, c: u8) {
+        if let Some(limit) = self.output_limit.filter(|&l| self.output_written >= l) {
+            self.halted = Some(HaltReason::OutputLimit(limit));
+            return;
+        }
+        self.output_written += 1;
+        self.output.write_all(&[c]).expect("failed to write");
+    }
+
+    // write a character a trap prints, with its high bit cleared if asked
+    fn put_text(&mut self, c: u8) {
+        let c = if self.strip_high_bit { c & 0x7F } else { c };
+        self.put_char(c);
+    }
+
+    // push buffered console output out, e.g. to a file before the process exits
+    pub fn flush(&mut self) {
+        self.output.flush().expect("failed to flush");
+    }
+
+    // dispatch TRAP through the trap vector table at 0x0000-0x00FF,
+    // used when an OS image providing the handlers is loaded
+    pub fn set_trap_table(&mut self, on: bool) {
+        self.trap_table = on;
+    }
+
+    // track signed overflow in ADD. this is not part of the LC-3, which has
+    // only the N, Z and P condition codes, and doesn't change them
+    pub fn set_overflow_tracking(&mut self, on: bool) {
+        self.overflow = on.then_some(false);
+    }
+
+    pub fn overflow_tracking(&self) -> bool {
+        self.overflow.is_some()
+    }
+
+    // did the last ADD overflow as a signed addition, always false unless tracking is on
+    pub fn overflow(&self) -> bool {
+        self.overflow == Some(true)
+    }
+
+    // the bytes the program has written to its console output
+    pub fn output_len(&self) -> u64 {
+        self.output_written
+    }
+
+    // stop a program that writes more than limit bytes, e.g. a PUTS running
+    // through memory without a terminator
+    pub fn set_output_limit(&mut self, limit: Option<u64>) {
+        self.output_limit = limit;
+    }
+
+    // stop at the reserved opcode instead of skipping it, a program that ran
+    // off into data or unwritten memory usually hits one soon
+    pub fn set_strict_opcodes(&mut self, on: bool) {
+        self.strict_opcodes = on;
+    }
+
+    // write every key read back to the output, as the LC-3 OS' console
+    // would. the machine itself doesn't, GETC and the keyboard register are silent
+    pub fn set_echo(&mut self, on: bool) {
+        self.echo = on;
+    }
+
+    // catch stores through a null pointer, which land in the trap vector
+    // table at x0000-x00FF: a program store (ST, STR, STI or a trap writing
+    // a buffer) into range stops the VM with abort, else warns on stderr
+    pub fn set_null_guard(&mut self, range: Option<(u16, u16)>, abort: bool) {
+        self.null_guard = range;
+        self.null_guard_abort = abort;
+    }
+
+    // replace the byte from with to wherever the program reads it, through
+    // GETC, IN or KBDR, e.g. LF with the CR a program waits for as Enter
+    pub fn map_key(&mut self, from: u8, to: u8) {
+        self.key_map.insert(from, to);
+    }
+
+    // stop with InfiniteLoop once the whole machine state after an
+    // instruction recurs, remembering the last capacity states, see loops.rs
+    // for what it can miss. None turns it off
+    pub fn set_loop_detection(&mut self, capacity: Option<usize>) {
+        self.loops = capacity.map(|c| LoopDetector::new(c, &self.memory));
+    }
+
+    // service interrupts: the keyboard's, once the program sets the
+    // interrupt enable bit of KBSR, and any from request_interrupt. a
+    // device interrupts only at a priority above the current priority level,
+    // a lower one waits until the level drops. the keyboard is read as soon
+    // as its interrupt is enabled, like a poll of KBSR, so on a terminal the
+    // VM waits there for a key. with the input exhausted it never interrupts
+    pub fn set_interrupts(&mut self, on: bool) {
+        self.interrupts = on;
+    }
+
+    // raise the interrupt vector at priority 1-7, serviced before the next
+    // instruction once the priority is above the current level. one request
+    // is kept per level, a second at the same level replaces the first.
+    // priority 0 could never interrupt and above 7 doesn't fit the PSR
+    pub fn request_interrupt(&mut self, vector: u8, priority: u16) -> Result<(), String> {
+        if !(1..8).contains(&priority) {
+            return Err(format!("an interrupt's priority is 1-7, not {}", priority));
+        }
+        self.status.requests[priority as usize] = Some(vector);
+        Ok(())
+    }
+
+    // the processor status register: 1 in bit 15 for user mode, the
+    // priority level in bits 10-8 and COND in bits 2-0
+    pub fn psr(&self) -> u16 {
+        (self.status.user as u16) << 15 | self.status.priority << 8 | self.read_reg(COND) & 0x7
+    }
+
+    // restore the PSR as RTI does, switching stacks on a change of mode
+    fn set_psr(&mut self, psr: u16) {
+        let user = psr >> 15 == 1;
+        if user && !self.status.user {
+            self.status.saved_ssp = self.read_reg(R6);
+            self.write_reg(R6, self.status.saved_usp);
+        }
+        self.status.user = user;
+        self.status.priority = (psr >> 8) & 0x7;
+        self.write_reg(COND, psr & 0x7);
+    }
+
+    // the highest priority interrupt above the current level, as its vector and priority
+    fn pending_interrupt(&mut self) -> Option<(u8, u16)> {
+        if !self.interrupts || !self.running() {
+            return None;
+        }
+        let kbsr = self.memory[MR_KBSR as usize];
+        if kbsr & KBSR_IE != 0 && !self.status.key_ready {
+            if let Some(c) = self.try_read_char() {
+                self.status.key_ready = true;
+                self.store(MR_KBDR, c as u16);
+                self.store(MR_KBSR, 1 << 15 | KBSR_IE);
+            }
+        }
+        let mut best = None;
+        if kbsr & KBSR_IE != 0 && self.status.key_ready {
+            best = Some((INT_KEYBOARD as u8, KEYBOARD_PRIORITY));
+        }
+        for (priority, vector) in self.status.requests.iter().enumerate() {
+            let priority = priority as u16;
+            if let Some(vector) = vector.filter(|_| best.is_none_or(|(_, p)| priority > p)) {
+                best = Some((vector, priority));
+            }
+        }
+        best.filter(|&(_, priority)| priority > self.status.priority)
+    }
+
+    // push the PSR and PC on the supervisor stack, switching to it from user
+    // mode, and jump to the handler in the interrupt vector table
+    fn enter_interrupt(&mut self, vector: u8, priority: u16) {
+        if self.status.requests[priority as usize] == Some(vector) {
+            self.status.requests[priority as usize] = None;
+        }
+        let psr = self.psr();
+        if self.status.user {
+            self.status.saved_usp = self.read_reg(R6);
+            self.write_reg(R6, self.status.saved_ssp);
+        }
+        let sp = self.read_reg(R6).wrapping_sub(2);
+        self.store(sp.wrapping_add(1), psr);
+        self.store(sp, self.read_reg(PC));
+        self.write_reg(R6, sp);
+        self.status.user = false;
+        self.status.priority = priority;
+        self.write_reg(COND, 0);
+        self.write_reg(PC, self.fetch(INT_TABLE + vector as u16));
+    }
+
+    // clear the high bit of every character OUT, PUTS and PUTSP print, for
+    // programs that store text with it set. off by default, the full byte is written
+    pub fn set_strip_high_bit(&mut self, on: bool) {
+        self.strip_high_bit = on;
+    }
+
+    // handle TRAP vector with a closure, ahead of the trap table and the
+    // built-in routines. when it runs R7 holds the return address and PC
+    // already points there, arguments and results are passed in R0-R6 and
+    // memory by whatever convention the trap defines, like the standard
+    // traps use R0
+    pub fn register_trap(&mut self, vector: u8, handler: TrapHandler) {
+        self.traps.insert(vector, handler);
+    }
+
+    // make TRAP x26 write the registers to stderr, for printf-style debugging.
+    // it isn't a standard trap, and like every trap it overwrites R7, the
+    // dump shows R7 as it was before the TRAP
+    pub fn set_debug_trap(&mut self, on: bool) {
+        self.debug_trap = on;
+    }
+
+    // write every register to out, one per line
+    pub fn dump_registers(&self, out: &mut dyn Write) -> io::Result<()> {
+        for (name, v) in REGISTER_NAMES.iter().zip(&self.regs) {
+            writeln!(out, "{:<8} x{:04X} {:>6}", name, v, *v as i16)?;
+        }
+        Ok(())
+    }
+
+    // observe execution from outside the VM, e.g. for coverage or tracing.
+    // a hook is a dynamic call per instruction, when none is set the only
+    // cost is checking for it
+    pub fn set_pre_step_hook(&mut self, hook: PreStepHook) {
+        self.pre_step_hook = Some(hook);
+    }
+
+    pub fn set_post_step_hook(&mut self, hook: PostStepHook) {
+        self.post_step_hook = Some(hook);
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.pre_step_hook = None;
+        self.post_step_hook = None;
+    }
+
+    // record which words of the regions, given as (origin, word count), get executed
+    pub fn enable_coverage(&mut self, regions: &[(u16, usize)]) {
+        self.coverage = Some(Coverage::new(regions));
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    // count how often each branch is taken and not taken
+    pub fn enable_branch_stats(&mut self) {
+        self.branches = Some(BranchStats::new());
+    }
+
+    pub fn branch_stats(&self) -> Option<&BranchStats> {
+        self.branches.as_ref()
+    }
+
+    // time each trap vector's handling, e.g. to see a program spending its
+    // time flushing output. with a trap table the vector only jumps to the
+    // OS routine, which runs as ordinary instructions, so only the built-in
+    // traps and installed handlers are timed meaningfully
+    pub fn enable_trap_times(&mut self) {
+        self.trap_times = Some(TrapTimes::new());
+    }
+
+    pub fn trap_times(&self) -> Option<&TrapTimes> {
+        self.trap_times.as_ref()
+    }
+
+    // remember what the last depth steps changed so step_back can undo them,
+    // 0 stops recording. this costs an allocation per step, leave it off
+    // outside the debugger
+    pub fn set_history(&mut self, depth: usize) {
+        self.history = (depth > 0).then(|| History { depth, steps: VecDeque::new(), current: None });
+    }
+
+    // undo the last recorded step, false when there is none. the registers
+    // and memory are restored, output already written and input already
+    // read are not
+    pub fn step_back(&mut self) -> bool {
+        let Some(delta) = self.history.as_mut().and_then(|h| h.steps.pop_back()) else {
+            return false;
+        };
+        for &(address, old) in delta.memory.iter().rev() {
+            self.set_word(address, old);
+        }
+        self.regs = delta.regs;
+        self.status = delta.status;
+        self.instructions = delta.instructions;
+        self.halted = delta.halted;
+        self.overflow = delta.overflow;
+        self.cycles = delta.cycles;
+        true
+    }
+
+    // the number of instructions executed since the VM was created
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    // count estimated cycles with these costs from now on, None stops counting
+    pub fn set_cycle_costs(&mut self, costs: Option<CycleCosts>) {
+        self.cycle_costs = costs;
+    }
+
+    // warn on stderr when the R6 stack leaves start..=end: when ADD R6, R6
+    // pushes or pops it out of the range, or when LDR or STR based on R6
+    // access a word outside it
+    pub fn set_stack(&mut self, range: Option<(u16, u16)>) {
+        self.stack = range;
+    }
+
+    // the estimated cycles executed, None unless costs are set
+    pub fn cycles(&self) -> Option<u64> {
+        self.cycle_costs.map(|_| self.cycles)
+    }
+
+    // stop the VM once it has spent limit cycles, or instructions when no
+    // costs are set
+    pub fn set_cycle_limit(&mut self, limit: Option<u64>) {
+        self.cycle_limit = limit;
+    }
+
+    // the cycles left under the limit, None without one
+    pub fn cycles_left(&self) -> Option<u64> {
+        let spent = self.cycles().unwrap_or(self.instructions);
+        self.cycle_limit.map(|limit| limit.saturating_sub(spent))
+    }
+
+    // warn on stderr when the program writes KBDR, or KBSR other than its
+    // interrupt enable bit, which are read-only on real hardware. the write
+    // still happens
+    pub fn set_strict_mmio(&mut self, on: bool) {
+        self.strict_mmio = on;
+    }
+
+    // log every TRAP on stderr with its vector and the registers it takes
+    pub fn set_trace_traps(&mut self, on: bool) {
+        self.trace_traps = on;
+    }
+
+    // attach the screen device, see screen.rs for its registers
+    pub fn enable_screen(&mut self) {
+        self.screen = Some(Screen::new());
+    }
+
+    pub fn screen(&self) -> Option<&Screen> {
+        self.screen.as_ref()
+    }
+
+    // handle TRAP_BUDGET, a non-standard trap real LC-3 software won't expect
+    pub fn set_budget_trap(&mut self, on: bool) {
+        self.budget_trap = on;
+    }
+
+    fn charge_memory_access(&mut self) {
+        if let Some(costs) = &self.cycle_costs {
+            self.cycles += costs.memory_access;
+        }
+    }
+
+    // is the VM still running
+    pub fn running(&self) -> bool {
+        self.halted.is_none()
+    }
+
+    // the reason the VM stopped, None while it's still running
+    pub fn halt_reason(&self) -> Option<HaltReason> {
+        self.halted
+    }
+
+    // what the VM does next as a Control, from why it stopped if it did
+    pub fn control(&self) -> Control {
+        match self.halted {
+            None => Control::Continue,
+            Some(HaltReason::Halt) => Control::Halt,
+            Some(reason) => Control::Error(reason),
+        }
+    }
+
+    // fetch one instruction and execute it
+    pub fn step(&mut self) -> Control {
+        if let Some((vector, priority)) = self.pending_interrupt() {
+            self.begin_delta();
+            self.enter_interrupt(vector, priority);
+            self.end_delta();
+            return match self.control() {
+                Control::Continue => Control::Interrupt,
+                stop => stop,
+            };
+        }
+        let pc_val = self.read_pc();
+        // the hook is taken out while it runs so it can borrow the VM
+        if let Some(mut hook) = self.pre_step_hook.take() {
+            hook(self, pc_val);
+            self.pre_step_hook = Some(hook);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.mark(pc_val);
+        }
+        self.begin_delta();
+        self.add_pc();
+        self.instructions += 1;
+        // fetching isn't a data load, running into xFE00 must not poll the keyboard
+        let instr = self.fetch(pc_val);
+        if let Some(costs) = &self.cycle_costs {
+            self.cycles += costs.opcodes[(instr >> 12) as usize];
+        }
+        let op = get_op(instr >> 12);
+        match op {
+            Op::BR => br(self, instr),
+            Op::ADD => add(self, instr),
+            Op::LD => ld(self, instr),
+            Op::ST => st(self, instr),
+            Op::JSR => jsr(self, instr),
+            Op::AND => and(self, instr),
+            Op::LDR => ldr(self, instr),
+            Op::STR => str(self, instr),
+            Op::NOT => not(self, instr),
+            Op::LDI => ldi(self, instr),
+            Op::STI => sti(self, instr),
+            Op::JMP => jmp(self, instr),
+            Op::LEA => lea(self, instr),
+            Op::TRAP => trap(self, instr),
+            Op::RTI => rti(self),
+            Op::RES | Op::Unknown if self.strict_opcodes => {
+                self.halted = Some(HaltReason::IllegalOpcode { address: pc_val, word: instr });
+            }
+            _ => {}
+        }
+        if let (Some(limit), true) = (self.cycle_limit, self.running()) {
+            if self.cycles_left() == Some(0) {
+                self.halted = Some(HaltReason::CycleLimit(limit));
+            }
+        }
+        if let (Some(mut loops), true) = (self.loops.take(), self.running()) {
+            if let Some(period) = loops.check(&self.regs, self.psr(), self.instructions) {
+                self.halted = Some(HaltReason::InfiniteLoop { pc: self.read_reg(PC), period });
+            }
+            self.loops = Some(loops);
+        }
+        self.end_delta();
+        if let Some(mut hook) = self.post_step_hook.take() {
+            hook(self, pc_val, instr);
+            self.post_step_hook = Some(hook);
+        }
+        self.control()
+    }
+
+    // start recording what the step about to run changes
+    fn begin_delta(&mut self) {
+        if let Some(history) = &mut self.history {
+            history.current = Some(Delta {
+                regs: self.regs.clone(),
+                status: self.status,
+                instructions: self.instructions,
+                halted: self.halted,
+                overflow: self.overflow,
+                cycles: self.cycles,
+                memory: Vec::new(),
+            });
+        }
+    }
+
+    fn end_delta(&mut self) {
+        if let Some(history) = &mut self.history {
+            if history.steps.len() == history.depth {
+                history.steps.pop_front();
+            }
+            history.steps.extend(history.current.take());
+        }
+    }
+
+    // run until the program halts, returning how it stopped
+    pub fn run(&mut self) -> Control {
+        loop {
+            match self.step() {
+                Control::Continue | Control::Interrupt => {}
+                stop => return stop,
+            }
+        }
+    }
+
+    // pass arguments to the program: the count goes to ARGC and the words to
+    // ARGV up to x2FFF, just below the usual program start. call it after
+    // loading, anything the image put there is overwritten
+    pub fn set_args(&mut self, args: &[u16]) -> Result<(), String> {
+        if args.len() > MAX_ARGS {
+            return Err(format!("at most {} arguments fit at x{:04X}, got {}", MAX_ARGS, ARGV, args.len()));
+        }
+        self.poke(ARGC, args.len() as u16);
+        for (i, &arg) in args.iter().enumerate() {
+            self.poke(ARGV + i as u16, arg);
+        }
+        Ok(())
+    }
+
+    // where images were loaded as (origin, word count), in load order
+    pub fn loaded_ranges(&self) -> &[(u16, usize)] {
+        &self.loaded
+    }
+
+    // load an image into memory, the first word is the origin,
+    // multi-section object files place each section at its own origin
+    pub fn load_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        for image in sections_from_bytes(bytes)? {
+            self.load_image(&image)?;
+        }
+        Ok(())
+    }
+
+    // load an image given as base64 text, for embedding without a file
+    pub fn load_base64(&mut self, text: &str) -> io::Result<()> {
+        self.load_bytes(&decode_base64(text)?)
+    }
+
+    // poke the words in, loading an image must not trigger device registers.
+    // an image running past xFFFF is refused before anything is written,
+    // wrapping around would overwrite low memory, and so is one reaching the
+    // device registers
+    pub fn load_image(&mut self, image: &Image) -> io::Result<()> {
+        image.check_fits()?;
+        image.check_devices()?;
+        self.loaded.push((image.origin, image.words.len()));
+        for (i, word) in image.words.iter().enumerate() {
+            self.poke(image.origin + i as u16, *word);
+        }
+        Ok(())
+    }
+}
+
+fn check_key() -> bool {
+    true
+}
+
+// read the image instruction to the memory
+pub fn read_image(image_path: &str, vm: &mut VM) -> io::Result<()> {
+    for image in read_sections(image_path)? {
+        vm.load_image(&image)?;
+    }
+    Ok(())
+}
+
+// if imm is positive or zero, return the imm
+// otherwise Pad with 1 from high to low
+pub fn sign_extend(imm: u16, len: i32) -> u16 {
+    if ((imm >> (len - 1)) & 0x1) == 0 {
+        imm
+    } else {
+        imm | (0xffff << len)
+    }
+}
+
+// branch when any of the instruction's n/z/p flags is set in COND,
+// with the flags field zero it never branches and is a no-op
+fn br(vm: &mut VM, instr: u16) {
+    let flags = (instr >> 9) & 0x7;
+    let pc_offset = sign_extend(instr & 0x1ff, 9);
+    let taken = (flags & vm.read_reg(COND)) != 0;
+    let address = vm.read_reg(PC).wrapping_sub(1);
+    if let Some(branches) = &mut vm.branches {
+        branches.record(address, taken);
+    }
+    if taken {
+        let pc_val = vm.read_pc();
+        vm.write_reg(PC, pc_val.wrapping_add(pc_offset));
+    }
+}
+
+fn add(vm: &mut VM, instr: u16) {
+    let r0 = (instr >> 9) & 0x7;
+    let r1 = (instr >> 6) & 0x7;
+    let imm5_flag = (instr >> 5) & 0x1;
+    let val1 = vm.read_reg_by_index(r1);
+    let val2 = if imm5_flag == 0 {
+        vm.read_reg_by_index(instr & 0x7)
+    } else {
+        // imm5 is #-16..#15, bit 4 set means negative and fills bits 5-15,
+        // so #-16 is xFFF0 and #15 stays x000F
+        sign_extend(instr & 0x1f, 5)
+    };
+    let res = val1.wrapping_add(val2);
+    vm.write_reg_by_index(r0, res);
+    vm.update_flags_by_index(r0);
+    // operands of the same sign and a result of the other sign
+    if let Some(overflow) = &mut vm.overflow {
+        *overflow = (val1 ^ res) & (val2 ^ res) & 0x8000 != 0;
+    }
+    if r0 == 6 && r1 == 6 {
+        check_stack(vm, res, "R6 moved to");
+    }
+}
+
+// warn when address is outside the declared stack
+fn check_stack(vm: &VM, address: u16, what: &str) {
+    if let Some((start, end)) = vm.stack.filter(|&(start, end)| !(start..=end).contains(&address)) {
+        let pc = vm.read_reg(PC).wrapping_sub(1);
+        eprintln!("warning: {} x{:04X} outside the stack x{:04X}-x{:04X} at x{:04X}", what, address, start, end, pc);
+    }
+}
+
+fn ld(vm: &mut VM, instr: u16) {
+    let r0 = (instr >> 9) & 0x7;
+    let pc_offset = sign_extend(instr & 0x1ff, 9);
+    let mem_add = vm.read_pc().wrapping_add(pc_offset);
+    let mem_val = vm.load(mem_add);
+    vm.write_reg_by_index(r0, mem_val);
+    vm.update_flags_by_index(r0);
+}
+
+// stores never set the condition codes, unlike the loads, COND is left as
+// the instruction before set it. the same holds for STR and STI
+fn st(vm: &mut VM, instr: u16) {
+    let r0 = (instr >> 9) & 0x7;
+    let pc_offset = sign_extend(instr & 0x1ff, 9);
+    let mem_add = vm.read_pc().wrapping_add(pc_offset);
+    vm.write_memory(mem_add, vm.read_reg_by_index(r0));
+}
+
+// the target is worked out before R7 takes the return address, so JSRR R7
+// jumps to R7's old value as the ISA specifies
+fn jsr(vm: &mut VM, instr: u16) {
+    let flag = (instr >> 11) & 0x1;
+    let target = if flag == 0 {
+        let base_r = (instr >> 6) & 0x7;
+        vm.read_reg_by_index(base_r)
+    } else {
+        let pc_offset = sign_extend(instr & 0x7ff, 11);
+        vm.read_reg(PC).wrapping_add(pc_offset)
+    };
+    vm.write_reg(R7, vm.read_reg(PC));
+    vm.write_reg(PC, target);
+}
+
+fn and(vm: &mut VM, instr: u16) {
+    let r0 = (instr >> 9) & 0x7;
+    let r1 = (instr >> 6) & 0x7;
+    let imm5_flag = (instr >> 5) & 0x1;
+    if imm5_flag == 0 {
+        let r2 = instr & 0x7;
+        vm.write_reg_by_index(r0, vm.read_reg_by_index(r1) & vm.read_reg_by_index(r2));
+    } else {
+        let imm5 = sign_extend(instr & 0x1f, 5);
+        vm.write_reg_by_index(r0, vm.read_reg_by_index(r1) & imm5);
+    }
+    vm.update_flags_by_index(r0);
+}
+
+fn ldr(vm: &mut VM, instr: u16) {
+    let r0 = (instr >> 9) & 0x7;
+    let r1 = (instr >> 6) & 0x7;
+    let offset = sign_extend(instr & 0x3f, 6);
+    let mem_add = vm.read_reg_by_index(r1).wrapping_add(offset);
+    if r1 == 6 {
+        check_stack(vm, mem_add, "LDR through R6 read");
+    }
+    let mem_val = vm.load(mem_add);
+    vm.write_reg_by_index(r0, mem_val);
+    vm.update_flags_by_index(r0);
+}
+
+fn str(vm: &mut VM, instr: u16) {
+    let r0 = (instr >> 9) & 0x7;
+    let r1 = (instr >> 6) & 0x7;
+    let offset = sign_extend(instr & 0x3f, 6);
+    let mem_add = vm.read_reg_by_index(r1).wrapping_add(offset);
+    if r1 == 6 {
+        check_stack(vm, mem_add, "STR through R6 wrote");
+    }
+    vm.write_memory(mem_add, vm.read_reg_by_index(r0));
+}
+
+fn not(vm: &mut VM, instr: u16) {
+    let r0 = (instr >> 9) & 0x7;
+    let r1 = (instr >> 6) & 0x7;
+    vm.write_reg_by_index(r0, !vm.read_reg_by_index(r1));
+    vm.update_flags_by_index(r0);
+}
+
+// return from an interrupt: pop PC and the PSR off the supervisor stack and
+// switch back to the user stack if the PSR is user mode's. in user mode RTI
+// is a privilege violation on the LC-3, here it does nothing, as it did
+// before interrupts were modeled
+fn rti(vm: &mut VM) {
+    if vm.status.user {
+        return;
+    }
+    let sp = vm.read_reg(R6);
+    let pc = vm.load(sp);
+    let psr = vm.load(sp.wrapping_add(1));
+    vm.write_reg(R6, sp.wrapping_add(2));
+    vm.write_reg(PC, pc);
+    vm.set_psr(psr);
+}
+
+// both accesses are data loads, as on the hardware's memory bus: a pointer
+// to KBSR polls the keyboard and one to DSR reads it ready, like LD or LDR
+// of those addresses would. the pointer's own cell is read the same way
+fn ldi(vm: &mut VM, instr: u16) {
+    let r0 = (instr >> 9) & 0x7;
+    let pc_offset = sign_extend(instr & 0x1ff, 9);
+    let first_mem_add = vm.read_pc().wrapping_add(pc_offset);
+    let second_mem_add = vm.load(first_mem_add);
+    let res = vm.load(second_mem_add);
+    vm.write_reg_by_index(r0, res);
+    vm.update_flags_by_index(r0);
+}
+
+// the pointer is read with a data load and the value stored with a data
+// write, so a pointer into device space writes that device register.
+// COND is untouched even though the pointer is loaded
+fn sti(vm: &mut VM, instr: u16) {
+    let r0 = (instr >> 9) & 0x7;
+    let pc_offset = sign_extend(instr & 0x1ff, 9);
+    let read_mem_add = vm.read_pc().wrapping_add(pc_offset);
+    let write_mem_add = vm.load(read_mem_add);
+    let val = vm.read_reg_by_index(r0);
+    vm.write_memory(write_mem_add, val);
+}
+
+fn jmp(vm: &mut VM, instr: u16) {
+    let r0 = (instr >> 6) & 0x7;
+    vm.write_reg(PC, vm.read_reg_by_index(r0));
+}
+
+fn lea(vm: &mut VM, instr: u16) {
+    let r0 = (instr >> 9) & 0x7;
+    let pc_offset = sign_extend(instr & 0x1ff, 9);
+    let val = vm.read_pc().wrapping_add(pc_offset);
+    vm.write_reg_by_index(r0, val);
+    vm.update_flags_by_index(r0);
+}
+
+fn warn_unterminated(name: &str, start: u16) {
+    eprintln!("warning: {} string at x{:04X} has no terminator, stopped after {} words", name, start, MEMORY_MAX);
+}
+
+// the TRAP at pc about to run, with R0 where the routine takes it
+fn trace_trap(vm: &VM, pc: u16, vector: u16) {
+    let name = trap_name(vector).map(|n| format!(" {}", n)).unwrap_or_default();
+    let r0 = vm.read_reg(R0);
+    let args = match vector {
+        TRAP_OUT => format!(": R0 = x{:04X} '{}'", r0, [r0 as u8].escape_ascii()),
+        TRAP_PUTS => {
+            // the start of the string, stopping at the terminator
+            let text: Vec<u8> = (0..40).map(|i| vm.peek(r0.wrapping_add(i)) as u8).take_while(|&c| c != 0).collect();
+            format!(": R0 = x{:04X} \"{}\"", r0, text.escape_ascii())
+        }
+        TRAP_PUTSP => format!(": R0 = x{:04X}", r0),
+        _ => String::new(),
+    };
+    eprintln!("TRAP x{:02X}{} at x{:04X}{}", vector, name, pc, args);
+}
+
+fn trap(vm: &mut VM, instr: u16) {
+    if vm.trap_times.is_none() {
+        return handle_trap(vm, instr);
+    }
+    let start = Instant::now();
+    handle_trap(vm, instr);
+    let time = start.elapsed();
+    if let Some(times) = &mut vm.trap_times {
+        times.record((instr & 0xff) as u8, time);
+    }
+}
+
+fn handle_trap(vm: &mut VM, instr: u16) {
+    if vm.trace_traps {
+        trace_trap(vm, vm.read_reg(PC).wrapping_sub(1), instr & 0xff);
+    }
+    if vm.debug_trap && instr & 0xff == TRAP_DEBUG {
+        // stdout is the program's, keep the dump off it
+        eprintln!("TRAP x{:02X} at x{:04X}:", TRAP_DEBUG, vm.read_reg(PC).wrapping_sub(1));
+        let _ = vm.dump_registers(&mut io::stderr());
+    }
+    let pc_val = vm.read_pc();
+    vm.write_reg(R7, pc_val);
+    let vector = (instr & 0xff) as u8;
+    if vm.budget_trap && instr & 0xff == TRAP_BUDGET {
+        // no limit means no end to the budget
+        let left = vm.cycles_left().unwrap_or(u64::MAX).min(u32::MAX as u64) as u32;
+        vm.write_reg(R0, left as u16);
+        vm.write_reg(R1, (left >> 16) as u16);
+        return;
+    }
+    if let Some(mut handler) = vm.traps.remove(&vector) {
+        handler(vm);
+        // the handler may have replaced itself
+        vm.traps.entry(vector).or_insert(handler);
+        return;
+    }
+    if vm.trap_table && !(vm.debug_trap && instr & 0xff == TRAP_DEBUG) {
+        // jump to the handler, it returns to R7 with RET
+        let handler = vm.load(instr & 0xff);
+        vm.write_reg(PC, handler);
+        return;
+    }
+    match instr & 0xff {
+        TRAP_GETC => {
+            let c = vm.read_char();
+            vm.write_reg(R0, c as u16);
+            vm.update_flags(R0);
+        }
+        TRAP_OUT => {
+            let c = vm.read_reg(R0) as u8;
+            vm.put_text(c);
+            vm.flush();
+        }
+        TRAP_PUTS => {
+            let mut start = vm.read_reg(R0);
+            let mut terminated = false;
+            // a string without its terminator would wrap around memory forever
+            for _ in 0..MEMORY_MAX {
+                let c = vm.load(start);
+                if c == 0 {
+                    terminated = true;
+                    break;
+                }
+                vm.put_text(c as u8);
+                start = start.wrapping_add(1);
+            }
+            vm.flush();
+            if !terminated {
+                warn_unterminated("PUTS", vm.read_reg(R0));
+            }
+        }
+        TRAP_IN => {
+            for c in b"Enter a character: " {
+                vm.put_char(*c);
+            }
+            vm.flush();
+            let c = vm.read_char();
+            if !vm.echo {
+                vm.put_char(c);
+                vm.flush();
+            }
+            vm.write_reg(R0, c as u16);
+            vm.update_flags(R0);
+        }
+        TRAP_PUTSP => {
+            // Putsp
+            let mut start_address = vm.read_reg(R0);
+            let mut terminated = false;
+            for _ in 0..MEMORY_MAX {
+                let c = vm.load(start_address);
+                if c == 0 {
+                    terminated = true;
+                    break;
+                }
+                // low 8 bits to char
+                let c1 = (c & 0xFF) as u8;
+                vm.put_text(c1);
+                // high 8 bits to char
+                let c2 = (c >> 8) as u8;
+                if c2 != 0 {
+                    vm.put_text(c2);
+                }
+                start_address = start_address.wrapping_add(1);
+            }
+            vm.flush();
+            if !terminated {
+                warn_unterminated("PUTSP", vm.read_reg(R0));
+            }
+        }
+        TRAP_HALT => {
+            vm.flush();
+            vm.halted = Some(HaltReason::Halt);
+        }
+        _ => {}
+    }
+}
+
+// the machine state as serde sees it: registers, the processor status with
+// its pending interrupts, the nonzero runs of memory and the settings. the
+// I/O streams, hooks, watches, limits and coverage aren't part of it, a
+// deserialized VM uses stdin and stdout like VM::new
+#[cfg(feature = "serde")]
+mod state {
+    use std::collections::BTreeMap;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use super::{HaltReason, Status, VM};
+
+    #[derive(Serialize, Deserialize)]
+    struct Run {
+        origin: u16,
+        words: Vec<u16>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct State {
+        registers: Vec<u16>,
+        status: Status,
+        instructions: u64,
+        memory: Vec<Run>,
+        halted: Option<HaltReason>,
+        interrupts: bool,
+        trap_table: bool,
+        debug_trap: bool,
+        echo: bool,
+        null_guard: Option<(u16, u16)>,
+        null_guard_abort: bool,
+        key_map: BTreeMap<u8, u8>,
+        strip_high_bit: bool,
+        strict_opcodes: bool,
+        strict_mmio: bool,
+        protected: Vec<(u16, u16)>,
+        overflow: Option<bool>,
+    }
+
+    fn runs(memory: &[u16]) -> Vec<Run> {
+        let mut runs: Vec<Run> = Vec::new();
+        for (address, &word) in memory.iter().enumerate().filter(|(_, w)| **w != 0) {
+            match runs.last_mut() {
+                Some(run) if run.origin as usize + run.words.len() == address => run.words.push(word),
+                _ => runs.push(Run { origin: address as u16, words: vec![word] }),
+            }
+        }
+        runs
+    }
+
+    impl Serialize for VM {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            State {
+                registers: self.regs.clone(),
+                status: self.status,
+                instructions: self.instructions,
+                memory: runs(&self.memory),
+                halted: self.halted,
+                interrupts: self.interrupts,
+                trap_table: self.trap_table,
+                debug_trap: self.debug_trap,
+                echo: self.echo,
+                null_guard: self.null_guard,
+                null_guard_abort: self.null_guard_abort,
+                key_map: self.key_map.clone(),
+                strip_high_bit: self.strip_high_bit,
+                strict_opcodes: self.strict_opcodes,
+                strict_mmio: self.strict_mmio,
+                protected: self.protected.clone(),
+                overflow: self.overflow,
+            }
+            .serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for VM {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<VM, D::Error> {
+            let state = State::deserialize(deserializer)?;
+            if state.registers.len() != super::REG_COUNT {
+                return Err(serde::de::Error::invalid_length(state.registers.len(), &"10 registers"));
+            }
+            let mut vm = VM::new();
+            vm.regs = state.registers;
+            for run in state.memory {
+                if run.origin as usize + run.words.len() > super::MEMORY_MAX {
+                    return Err(serde::de::Error::custom(format!("memory run at x{:04X} ends past xFFFF", run.origin)));
+                }
+                for (i, &word) in run.words.iter().enumerate() {
+                    vm.set_word(run.origin + i as u16, word);
+                }
+            }
+            if state.status.priority > 7 {
+                return Err(serde::de::Error::custom(format!("priority level {} is above 7", state.status.priority)));
+            }
+            vm.status = state.status;
+            vm.instructions = state.instructions;
+            vm.halted = state.halted;
+            vm.interrupts = state.interrupts;
+            vm.trap_table = state.trap_table;
+            vm.debug_trap = state.debug_trap;
+            vm.echo = state.echo;
+            vm.null_guard = state.null_guard;
+            vm.null_guard_abort = state.null_guard_abort;
+            vm.key_map = state.key_map;
+            vm.strip_high_bit = state.strip_high_bit;
+            vm.strict_opcodes = state.strict_opcodes;
+            vm.strict_mmio = state.strict_mmio;
+            vm.protected = state.protected;
+            vm.overflow = state.overflow;
+            Ok(vm)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+    use super::*;
+    use crate::testing::{assert_cond, assert_regs, Flag};
+    use crate::vm::RegisterType::{R2, R3, R4, R5};
+
+    // console output kept for the test to look at
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Capture {
+        fn text(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    // a VM without keyboard or console, with words placed from PC_START
+    fn vm_with(words: &[u16]) -> VM {
+        let mut vm = VM::with_io(Box::new(io::empty()), Box::new(io::sink()));
+        for (i, &word) in words.iter().enumerate() {
+            vm.poke(PC_START + i as u16, word);
+        }
+        vm
+    }
+
+    // the same with typed keys and the console captured
+    fn vm_with_io(keys: &str, words: &[u16]) -> (VM, Capture) {
+        let output = Capture::default();
+        let mut vm = VM::with_io(Box::new(Cursor::new(keys.as_bytes().to_vec())), Box::new(output.clone()));
+        for (i, &word) in words.iter().enumerate() {
+            vm.poke(PC_START + i as u16, word);
+        }
+        (vm, output)
+    }
+
+    #[test]
+    fn add_most_negative_immediate() {
+        // ADD R0, R0, #-16
+        let mut vm = vm_with(&[0x1030]);
+        vm.step();
+        assert_regs(&vm, &[(R0, 0xFFF0)]);
+        assert_cond(&vm, Flag::N);
+    }
+
+    #[test]
+    fn add_most_positive_immediate() {
+        // ADD R0, R0, #15
+        let mut vm = vm_with(&[0x102F]);
+        vm.step();
+        assert_regs(&vm, &[(R0, 0x000F)]);
+        assert_cond(&vm, Flag::P);
+    }
+
+    #[test]
+    fn add_immediates_cancel() {
+        // ADD R0, R0, #-16 ; ADD R0, R0, #15 ; ADD R0, R0, #1
+        let mut vm = vm_with(&[0x1030, 0x102F, 0x1021]);
+        vm.step();
+        vm.step();
+        assert_regs(&vm, &[(R0, 0xFFFF)]);
+        assert_cond(&vm, Flag::N);
+        vm.step();
+        assert_regs(&vm, &[(R0, 0)]);
+        assert_cond(&vm, Flag::Z);
+    }
+
+    #[test]
+    fn stores_leave_cond() {
+        // ST R0, #2 ; STR R0, R1, #0 ; STI R0, #1 ; x3003 is ST's target and
+        // x3004 STI's pointer to x4001
+        let mut vm = vm_with(&[0x3002, 0x7040, 0xB001, 0x1234, 0x4001]);
+        vm.write_reg(R1, 0x4000);
+        vm.poke(0x4000, 0xFFFF);
+        vm.poke(0x4001, 0xFFFF);
+        // R0 is zero, a store setting the flags from it would leave Z
+        vm.set_cond_flags(Flags { n: true, z: false, p: false });
+        for _ in 0..3 {
+            vm.step();
+            assert_cond(&vm, Flag::N);
+        }
+        assert_eq!((vm.peek(0x3003), vm.peek(0x4000), vm.peek(0x4001)), (0, 0, 0));
+    }
+
+    #[test]
+    fn ldi_reads_through_the_pointer() {
+        // LDI R0, #1 with its pointer at x3002 to x4000
+        let mut vm = vm_with(&[0xA001, 0, 0x4000]);
+        vm.poke(0x4000, 0x8001);
+        vm.step();
+        assert_regs(&vm, &[(R0, 0x8001)]);
+        assert_cond(&vm, Flag::N);
+        // the pointer itself is only read
+        assert_eq!(vm.peek(0x3002), 0x4000);
+    }
+
+    #[test]
+    fn ldi_pointer_cell_wraps_past_xffff() {
+        // LDI R0, #1 at xFFFF: PC wraps to x0000 first, the pointer is at x0001
+        let mut vm = vm_with(&[]);
+        vm.poke(0xFFFF, 0xA001);
+        vm.poke(0x0001, 0x4000);
+        vm.poke(0x4000, 7);
+        vm.write_reg(PC, 0xFFFF);
+        vm.step();
+        assert_regs(&vm, &[(R0, 7), (PC, 0x0000)]);
+        assert_cond(&vm, Flag::P);
+    }
+
+    #[test]
+    fn ldi_pointer_to_kbsr_polls_the_keyboard() {
+        let (mut vm, _) = vm_with_io("a", &[0xA001, 0, MR_KBSR]);
+        vm.step();
+        assert_regs(&vm, &[(R0, 1 << 15)]);
+        assert_eq!(vm.peek(MR_KBDR), b'a' as u16);
+    }
+
+    #[test]
+    fn sti_writes_through_the_pointer() {
+        // STI R0, #1 with its pointer at x3002 to x4000
+        let mut vm = vm_with(&[0xB001, 0, 0x4000]);
+        vm.write_reg(R0, 0xBEEF);
+        vm.step();
+        assert_eq!(vm.peek(0x4000), 0xBEEF);
+        assert_eq!(vm.peek(0x3002), 0x4000);
+    }
+
+    #[test]
+    fn sti_pointer_to_ddr_prints() {
+        let (mut vm, output) = vm_with_io("", &[0xB001, 0, MR_DDR]);
+        vm.write_reg(R0, b'A' as u16);
+        vm.step();
+        assert_eq!(output.text(), "A");
+    }
+
+    #[test]
+    fn br_branches_on_any_matching_flag() {
+        for cond in [Flags::N, Flags::Z, Flags::P] {
+            for nzp in 0..8 {
+                // BR with the flags nzp and offset #5
+                let mut vm = vm_with(&[nzp << 9 | 5]);
+                vm.set_cond_flags(Flags::from_bits(cond));
+                vm.step();
+                let taken = nzp & cond != 0;
+                let want = if taken { 0x3006 } else { 0x3001 };
+                assert_eq!(vm.read_reg(PC), want, "nzp {:03b} with COND {:03b}", nzp, cond);
+            }
+        }
+    }
+
+    #[test]
+    fn br_without_flags_never_branches() {
+        // x0000 and BR #-1 with no flags, whatever COND holds, even none
+        for cond in 0..8 {
+            let mut vm = vm_with(&[0x0000, 0x01FF]);
+            vm.write_reg(COND, cond);
+            vm.step();
+            vm.step();
+            assert_regs(&vm, &[(PC, 0x3002)]);
+        }
+    }
+
+    #[test]
+    fn runs_on_another_thread() {
+        // LEA R0, #2 ; PUTS ; HALT ; "hi"
+        let (mut vm, output) = vm_with_io("", &[0xE002, 0xF022, 0xF025, b'h' as u16, b'i' as u16, 0]);
+        let worker = std::thread::spawn(move || {
+            let control = vm.run();
+            (control, vm.instructions())
+        });
+        let (control, instructions) = worker.join().unwrap();
+        assert_eq!(control, Control::Halt);
+        assert_eq!(instructions, 3);
+        assert_eq!(output.text(), "hi");
+    }
+
+    #[test]
+    fn arithmetic_wraps_without_panicking() {
+        // ADD R0, R0, #1 ; ADD R1, R1, #1 ; ADD R2, R2, R3, overflowing each way
+        let mut vm = vm_with(&[0x1021, 0x1261, 0x1483]);
+        vm.write_reg(R0, 0x7FFF);
+        vm.write_reg(R1, 0xFFFF);
+        vm.write_reg(R2, 0x8000);
+        vm.write_reg(R3, 0x8000);
+        for _ in 0..3 {
+            vm.step();
+        }
+        assert_regs(&vm, &[(R0, 0x8000), (R1, 0), (R2, 0)]);
+        // at xFFFF: LEA R4, #-1 after PC wrapped to x0000 ; x0000: LDR R5, R6, #-1
+        // with R6 zero ; x0001: BRnzp #-3 back to xFFFF ; x0002: JSR #-3 to xFFFF
+        vm.poke(0xFFFF, 0xE9FF);
+        vm.poke(0x0000, 0x6BBF);
+        vm.poke(0x0001, 0x0FFD);
+        vm.write_reg(PC, 0xFFFF);
+        vm.step();
+        assert_regs(&vm, &[(R4, 0xFFFF), (PC, 0x0000)]);
+        vm.step();
+        assert_regs(&vm, &[(R5, 0xE9FF)]);
+        vm.step();
+        assert_regs(&vm, &[(PC, 0xFFFF)]);
+        vm.poke(0x0002, 0x4FFD);
+        vm.write_reg(PC, 0x0002);
+        vm.step();
+        assert_regs(&vm, &[(PC, 0x0000), (R7, 0x0003)]);
+    }
+
+    #[test]
+    fn puts_stops_on_an_unterminated_string() {
+        // no zero word anywhere, the walk from xFFF0 wraps and comes round.
+        // the key is for the poll when the walk reads KBSR
+        let (mut vm, output) = vm_with_io("x", &[]);
+        vm.fill_memory(b'A' as u16);
+        vm.poke(PC_START, 0xF022);
+        vm.write_reg(R0, 0xFFF0);
+        assert_eq!(vm.step(), Control::Continue);
+        assert_eq!(output.text().len(), MEMORY_MAX);
+        assert!(output.text().starts_with("AAAA"));
+        assert_regs(&vm, &[(PC, PC_START + 1)]);
+    }
+
+    #[test]
+    fn protected_write_stops_the_vm() {
+        // STR R0, R1, #0 into x4010 inside the protected x4000-x40FF
+        let mut vm = vm_with(&[0x7040]);
+        vm.poke(0x4010, 0x1111);
+        vm.protect(0x4000, 0x40FF);
+        vm.write_reg(R0, 0x2222);
+        vm.write_reg(R1, 0x4010);
+        assert_eq!(vm.step(), Control::Error(HaltReason::WriteProtected(0x4010)));
+        assert_eq!(vm.peek(0x4010), 0x1111);
+    }
+
+    #[test]
+    fn device_registers_stay_writable() {
+        // STI R0, #1 through a pointer to DDR with all of memory protected
+        let (mut vm, output) = vm_with_io("", &[0xB001, 0, MR_DDR]);
+        vm.protect(0x0000, 0xFFFF);
+        vm.write_reg(R0, b'A' as u16);
+        assert_eq!(vm.step(), Control::Continue);
+        assert_eq!(output.text(), "A");
+    }
+
+    #[test]
+    fn fetching_from_kbsr_consumes_no_key() {
+        // KBSR holds zero, a BR without flags, after it GETC runs at x3000
+        let (mut vm, _) = vm_with_io("a", &[0xF020]);
+        vm.write_reg(PC, MR_KBSR);
+        vm.step();
+        assert_eq!((vm.peek(MR_KBSR), vm.peek(MR_KBDR)), (0, 0));
+        vm.write_reg(PC, PC_START);
+        vm.step();
+        assert_regs(&vm, &[(R0, b'a' as u16)]);
+    }
+
+    #[test]
+    fn fetch_leaves_devices_alone_and_load_polls() {
+        let (mut vm, _) = vm_with_io("k", &[]);
+        assert_eq!(vm.fetch(MR_KBSR), 0);
+        assert_eq!(vm.fetch(MR_DSR), 0);
+        assert_eq!(vm.load(MR_KBSR), 1 << 15);
+        assert_eq!(vm.load(MR_KBDR), b'k' as u16);
+        assert_eq!(vm.load(MR_DSR), 1 << 15);
+    }
+
+    #[test]
+    fn data_loads_read_devices() {
+        // LDR R0, R1, #0 with R1 at DSR ; LD R2, #1 of x3003
+        let mut vm = vm_with(&[0x6040, 0x2401, 0, 0x5555]);
+        vm.write_reg(R1, MR_DSR);
+        vm.step();
+        vm.step();
+        assert_regs(&vm, &[(R0, 1 << 15), (R2, 0x5555)]);
+    }
+
+    #[test]
+    fn cond_starts_at_zero_flag() {
+        assert_cond(&VM::with_io(Box::new(io::empty()), Box::new(io::sink())), Flag::Z);
+        // BRz #3 straight after reset
+        let mut vm = vm_with(&[0x0403]);
+        vm.step();
+        assert_regs(&vm, &[(PC, 0x3004)]);
+    }
+
+    #[test]
+    fn oversized_image_writes_nothing() {
+        let mut vm = vm_with(&[]);
+        let image = Image { origin: 0xFFF0, words: vec![1; 0x20] };
+        assert!(vm.load_bytes(&image.to_bytes()).is_err());
+        // neither the words that fit nor those that would wrap to x0000
+        assert!((0xFFF0..=0xFFFF).chain(0..0x10).all(|a| !vm.was_written(a)));
+        assert!(vm.loaded_ranges().is_empty());
+    }
+
+    #[test]
+    fn null_guard_stops_a_store_to_x0000() {
+        // STR R0, R1, #0 with R1 zero
+        let mut vm = vm_with(&[0x7040]);
+        vm.poke(0x0000, 0x0400);
+        vm.write_reg(R0, 0xFFFF);
+        vm.set_null_guard(Some((0x0000, 0x00FF)), true);
+        assert_eq!(vm.step(), Control::Error(HaltReason::NullWrite { address: 0x0000, pc: 0x3000 }));
+        assert_eq!(vm.peek(0x0000), 0x0400);
+    }
+
+    #[test]
+    fn null_guard_warns_without_abort_and_keeps_to_its_range() {
+        // STR R0, R1, #0 with R1 zero, then STR R0, R2, #0 with R2 at x0100
+        let mut vm = vm_with(&[0x7040, 0x7080]);
+        vm.write_reg(R0, 0xFFFF);
+        vm.write_reg(R2, 0x0100);
+        vm.set_null_guard(Some((0x0000, 0x00FF)), false);
+        assert_eq!(vm.step(), Control::Continue);
+        assert_eq!(vm.step(), Control::Continue);
+        assert_eq!((vm.peek(0x0000), vm.peek(0x0100)), (0xFFFF, 0xFFFF));
+        // with the guard off nothing is checked, as by default
+        vm.set_null_guard(None, true);
+        vm.write_reg(PC, PC_START);
+        assert_eq!(vm.step(), Control::Continue);
+    }
+
+    #[test]
+    fn decode_register_and_immediate_operands() {
+        // ADD R3, R4, R5
+        let d = decode(0x1705);
+        assert_eq!((d.op, d.dr, d.sr1, d.sr2, d.imm_mode), (Op::ADD, 3, 4, 5, false));
+        // AND R1, R2, #-7
+        let d = decode(0x52B9);
+        assert_eq!((d.op, d.dr, d.sr1, d.imm_mode, d.imm5), (Op::AND, 1, 2, true, -7));
+        // NOT R6, R7
+        let d = decode(0x9DFF);
+        assert_eq!((d.op, d.dr, d.sr1), (Op::NOT, 6, 7));
+    }
+
+    #[test]
+    fn decode_offsets_are_sign_extended() {
+        // LD R2, #-256 and BRnp #255
+        let d = decode(0x2500);
+        assert_eq!((d.op, d.dr, d.pc_offset9), (Op::LD, 2, -256));
+        let d = decode(0x0AFF);
+        assert_eq!((d.op, d.nzp, d.pc_offset9), (Op::BR, 0b101, 255));
+        // LDR R0, R6, #-32 and STR R7, R1, #31
+        let d = decode(0x61A0);
+        assert_eq!((d.op, d.dr, d.base_r, d.offset6), (Op::LDR, 0, 6, -32));
+        let d = decode(0x7E5F);
+        assert_eq!((d.op, d.dr, d.base_r, d.offset6), (Op::STR, 7, 1, 31));
+    }
+
+    #[test]
+    fn decode_jumps_and_traps() {
+        // JSR #-1024, JSRR R3, RET and TRAP x25
+        let d = decode(0x4C00);
+        assert_eq!((d.op, d.jsr_offset, d.pc_offset11), (Op::JSR, true, -1024));
+        let d = decode(0x40C0);
+        assert_eq!((d.op, d.jsr_offset, d.base_r), (Op::JSR, false, 3));
+        let d = decode(0xC1C0);
+        assert_eq!((d.op, d.base_r), (Op::JMP, 7));
+        let d = decode(0xF025);
+        assert_eq!((d.op, d.trap_vect), (Op::TRAP, 0x25));
+        assert_eq!((decode(0x8000).op, decode(0xD000).op), (Op::RTI, Op::RES));
+    }
+
+    #[test]
+    fn loading_at_xfe00_fails() {
+        let mut vm = vm_with(&[]);
+        let image = Image { origin: 0xFE00, words: vec![0x8000] };
+        assert!(vm.load_image(&image).is_err());
+        assert_eq!(vm.peek(MR_KBSR), 0);
+    }
+
+    #[test]
+    fn cond_flags_round_trip() {
+        let mut vm = vm_with(&[]);
+        for bits in 0..8 {
+            let flags = Flags::from_bits(bits);
+            assert_eq!(flags.bits(), bits);
+            vm.set_cond_flags(flags);
+            assert_eq!(vm.read_reg(COND), bits);
+            assert_eq!(vm.cond_flags(), flags);
+        }
+        // N is bit 2 and P bit 0, as BR's nzp field
+        assert_eq!(Flags::from_bits(0b100), Flags { n: true, z: false, p: false });
+        assert_eq!(Flags::from_bits(0b1000_0001), Flags { n: false, z: false, p: true });
+        assert_eq!((Flags::of(0x8000), Flags::of(0), Flags::of(0x7FFF)), (Flags::from_bits(4), Flags::from_bits(2), Flags::from_bits(1)));
+    }
+
+    #[test]
+    fn interrupt_priority_outside_1_to_7_is_an_error() {
+        let mut vm = vm_with(&[]);
+        assert_eq!(vm.request_interrupt(0x81, 0), Err("an interrupt's priority is 1-7, not 0".to_string()));
+        assert!(vm.request_interrupt(0x81, 8).is_err());
+        assert!(vm.request_interrupt(0x81, 7).is_ok());
+    }
+
+    #[test]
+    fn lower_priority_interrupt_waits_for_the_level_to_drop() {
+        let mut vm = vm_with(&[]);
+        vm.set_interrupts(true);
+        // the handler for x81 is a BR without flags then RTI, x82's is at x5000
+        vm.poke(INT_TABLE + 0x81, 0x4000);
+        vm.poke(INT_TABLE + 0x82, 0x5000);
+        vm.poke(0x4001, 0x8000);
+        vm.request_interrupt(0x81, 5).unwrap();
+        assert_eq!(vm.step(), Control::Interrupt);
+        assert_eq!((vm.read_reg(PC), vm.psr() >> 8 & 0x7), (0x4000, 5));
+        // at priority 3 it waits while the handler at level 5 runs
+        vm.request_interrupt(0x82, 3).unwrap();
+        assert_eq!(vm.step(), Control::Continue);
+        assert_regs(&vm, &[(PC, 0x4001)]);
+        // RTI drops the level back to 0, then it's taken
+        assert_eq!(vm.step(), Control::Continue);
+        assert_eq!((vm.read_reg(PC), vm.psr() >> 8 & 0x7), (0x3000, 0));
+        assert_eq!(vm.step(), Control::Interrupt);
+        assert_eq!((vm.read_reg(PC), vm.psr() >> 8 & 0x7), (0x5000, 3));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn state_round_trips_through_json() {
+        // STR R0, R1, #0 ; the reserved opcode
+        let mut vm = vm_with(&[0x7040, 0xD000]);
+        vm.poke(0x4000, 0x1234);
+        vm.protect(0x5000, 0x50FF);
+        vm.set_null_guard(Some((0x0000, 0x00FF)), true);
+        vm.set_strict_opcodes(true);
+        vm.set_strict_mmio(true);
+        vm.set_strip_high_bit(true);
+        vm.map_key(b'\n', b'\r');
+        // in supervisor mode in an interrupt handler at level 6, with a
+        // request at 2 pending and the user stack pointer saved
+        vm.set_interrupts(true);
+        vm.poke(INT_TABLE + 0x81, PC_START);
+        vm.write_reg(R6, 0xBEEF);
+        vm.request_interrupt(0x81, 6).unwrap();
+        vm.request_interrupt(0x82, 2).unwrap();
+        assert_eq!(vm.step(), Control::Interrupt);
+        vm.write_reg(R1, 0x000F);
+
+        let json = serde_json::to_string(&vm).unwrap();
+        let mut copy: VM = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&copy).unwrap(), json);
+        assert_eq!(copy.registers(), vm.registers());
+        assert_eq!(copy.status, vm.status);
+        assert_eq!((copy.psr(), copy.instructions()), (0x0600, 0));
+        assert_eq!(copy.status.requests[2], Some(0x82));
+        assert_eq!(copy.status.saved_usp, 0xBEEF);
+        assert_eq!((copy.peek(0x4000), copy.peek(PC_START)), (0x1234, 0x7040));
+        assert_eq!(copy.key_map, vm.key_map);
+        assert!(copy.strict_mmio && copy.strip_high_bit);
+        // the null guard, then strict opcodes, still stop the copy
+        assert_eq!(copy.step(), Control::Error(HaltReason::NullWrite { address: 0x000F, pc: PC_START }));
+        copy.set_null_guard(None, false);
+        copy.halted = None;
+        copy.write_reg(PC, PC_START + 1);
+        assert_eq!(copy.step(), Control::Error(HaltReason::IllegalOpcode { address: PC_START + 1, word: 0xD000 }));
+        assert_eq!(copy.protected, [(0x5000, 0x50FF)]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn state_with_a_bad_priority_is_refused() {
+        let json = serde_json::to_string(&vm_with(&[])).unwrap();
+        let bad = json.replace("\"priority\":0", "\"priority\":8");
+        assert_ne!(bad, json);
+        assert!(serde_json::from_str::<VM>(&bad).is_err());
+    }
+
+    #[test]
+    fn jsr_reaches_both_ends_of_its_offset() {
+        // JSR #-1024 and JSR #1023 from x3000
+        for (instr, target) in [(0x4C00, 0x2C01), (0x4BFF, 0x3400)] {
+            let mut vm = vm_with(&[instr]);
+            vm.step();
+            assert_regs(&vm, &[(PC, target), (R7, 0x3001)]);
+        }
+    }
+
+    #[test]
+    fn jsr_offset_wraps_below_x0000() {
+        // JSR #-16 at x0000
+        let mut vm = vm_with(&[]);
+        vm.poke(0x0000, 0x4FF0);
+        vm.write_reg(PC, 0x0000);
+        vm.step();
+        assert_regs(&vm, &[(PC, 0xFFF1), (R7, 0x0001)]);
+    }
+
+    #[test]
+    fn jsrr_jumps_to_the_base_register() {
+        // JSRR R3
+        let mut vm = vm_with(&[0x40C0]);
+        vm.write_reg(R3, 0x4567);
+        vm.step();
+        assert_regs(&vm, &[(PC, 0x4567), (R7, 0x3001), (R3, 0x4567)]);
+    }
+
+    #[test]
+    fn jsrr_r7_jumps_to_the_old_r7() {
+        // JSRR R7, the target is read before R7 takes the return address
+        let mut vm = vm_with(&[0x41C0]);
+        vm.write_reg(R7, 0x5000);
+        vm.step();
+        assert_regs(&vm, &[(PC, 0x5000), (R7, 0x3001)]);
+    }
+
+    #[test]
+    fn core_dump_region_round_trips_as_an_image() {
+        let mut vm = vm_with(&[0x1021, 0xF025]);
+        vm.poke(0x0000, 0x1234);
+        vm.poke(0xFDFF, 0xBEEF);
+        let bytes = vm.export_region(0, MR_KBSR as usize).to_bytes();
+        let sections = crate::image::sections_from_bytes(&bytes).unwrap();
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].origin, 0x0000);
+        assert_eq!(sections[0].words.len(), 0xFE00);
+        assert_eq!(sections[0].words, vm.memory[..0xFE00]);
+        assert_eq!(sections[0].words[0xFDFF], 0xBEEF);
+        // loaded back into a fresh VM it's the same memory
+        let mut copy = vm_with(&[]);
+        copy.load_bytes(&bytes).unwrap();
+        assert!((0..MR_KBSR).all(|a| copy.peek(a) == vm.peek(a)));
+    }
+}